@@ -0,0 +1,116 @@
+//! Benchmarks for deserializing attributes from a realistic set of request headers.
+//!
+//! To measure a change, save a baseline before making it, and compare against it afterwards:
+//!
+//! ```text
+//! cargo bench --bench deserialize -- --save-baseline before
+//! cargo bench --bench deserialize -- --baseline before
+//! ```
+//!
+//! Each iteration deserializes from newly built headers, excluded from the measurement, as it would
+//! for a new request. Reusing one set of headers would measure values which hyper has cached.
+
+#[macro_use]
+extern crate criterion;
+extern crate hyper;
+#[macro_use]
+extern crate serde_derive;
+
+extern crate shib_gotham;
+
+use criterion::{BatchSize, Criterion};
+use hyper::Headers;
+use std::collections::HashMap;
+
+use shib_gotham::deserialize_attributes;
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct UserAttributes {
+    #[serde(rename = "auEduPersonSharedToken")]
+    shared_token: String,
+
+    #[serde(rename = "displayName")]
+    display_name: String,
+
+    #[serde(rename = "mail")]
+    email: String,
+
+    #[serde(rename = "eduPersonEntitlement")]
+    entitlements: Vec<String>,
+
+    #[serde(rename = "givenName")]
+    given_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[allow(dead_code)]
+struct SessionAttributes {
+    #[serde(rename = "Shib-Session-ID")]
+    session_id: String,
+
+    #[serde(rename = "Shib-Identity-Provider")]
+    identity_provider: String,
+}
+
+/// A realistic request, with ordinary browser headers alongside the attributes.
+fn request_headers() -> Headers {
+    let mut headers = Headers::new();
+    headers.set_raw("Host", "sp.example.edu.au");
+    headers.set_raw(
+        "User-Agent",
+        "Mozilla/5.0 (X11; Linux x86_64; rv:57.0) Gecko/20100101 Firefox/57.0",
+    );
+    headers.set_raw("Accept", "text/html,application/xhtml+xml");
+    headers.set_raw("Accept-Language", "en-AU,en;q=0.5");
+    headers.set_raw("Cookie", "_gotham_session=QmV5b25kIHRoZSBzZXNzaW9u");
+    headers.set_raw("Shib-Session-ID", "_7ec5a10e9d8b8f8ffbd5e0a7d04d4a3c");
+    headers.set_raw(
+        "Shib-Identity-Provider",
+        "https://idp.example.edu.au/idp/shibboleth",
+    );
+    headers.set_raw("auEduPersonSharedToken", "BuyTkNadqZW_wYOeY4ppThkRRYE");
+    headers.set_raw("displayName", "John Doe");
+    headers.set_raw("mail", "john.doe@example.edu.au");
+    headers.set_raw("givenName", "John");
+    headers.set_raw(
+        "eduPersonEntitlement",
+        "urn:x-aaf:dev:1;urn:x-aaf:dev:2;urn:x-aaf:dev:3",
+    );
+    headers
+}
+
+fn struct_benchmark(c: &mut Criterion) {
+    c.bench_function("deserialize struct", |b| {
+        b.iter_batched_ref(
+            request_headers,
+            |headers| deserialize_attributes::<UserAttributes>(headers).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn tuple_benchmark(c: &mut Criterion) {
+    c.bench_function("deserialize tuple", |b| {
+        b.iter_batched_ref(
+            request_headers,
+            |headers| {
+                deserialize_attributes::<(UserAttributes, SessionAttributes)>(headers).unwrap()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn map_benchmark(c: &mut Criterion) {
+    c.bench_function("deserialize map", |b| {
+        b.iter_batched_ref(
+            request_headers,
+            |headers| deserialize_attributes::<HashMap<String, String>>(headers).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, struct_benchmark, tuple_benchmark, map_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,33 @@
+//! Prints the requested attributes for an attributes type, for inclusion in the SP metadata which
+//! is registered with the federation.
+
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+extern crate shib_gotham;
+
+use shib_gotham::RequestedAttributes;
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+struct UserAttributes {
+    #[serde(rename = "auEduPersonSharedToken")]
+    shared_token: String,
+
+    #[serde(rename = "mail")]
+    email: String,
+
+    #[serde(rename = "displayName")]
+    display_name: Option<String>,
+
+    #[serde(rename = "eduPersonEntitlement")]
+    entitlements: Option<Vec<String>>,
+}
+
+fn main() {
+    let requested = RequestedAttributes::of::<UserAttributes>().unwrap();
+
+    println!("{}", requested.to_xml("shib-gotham Example"));
+    println!("{}", requested.to_json());
+}
@@ -13,12 +13,13 @@ use log::LevelFilter;
 use hyper::{Response, StatusCode};
 use gotham::pipeline::new_pipeline;
 use gotham::pipeline::set::*;
-use gotham::middleware::session::{NewSessionMiddleware, SessionData};
+use gotham::middleware::session::{MemoryBackend, NewSessionMiddleware, SessionData};
 use gotham::http::response::create_response;
 use gotham::router::Router;
 use gotham::router::builder::*;
 use gotham::state::{FromState, State};
-use shib_gotham::{AuthenticatedSession, ReceiverFailed, Shibbleware};
+use shib_gotham::{AuthRouterConfig, AuthenticatedSession, ReceiverFailed, ShibSessionState,
+                  Shibbleware};
 
 fn main() {
     set_logging();
@@ -50,12 +51,21 @@ fn set_logging() {
 #[derive(Default, Serialize, Deserialize)]
 struct Session {
     user: Option<UserAttributes>,
+    shib: ShibSessionState,
 }
 
 impl AuthenticatedSession for Session {
     fn is_authenticated(&self) -> bool {
         self.user.is_some()
     }
+
+    fn shib_state(&self) -> Option<&ShibSessionState> {
+        Some(&self.shib)
+    }
+
+    fn shib_state_mut(&mut self) -> Option<&mut ShibSessionState> {
+        Some(&mut self.shib)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -78,7 +88,7 @@ mod controller {
                 </head>
                 <body>
                     <h2>Welcome</h2>
-                    <p><a href="/auth/login">Login</a></p>
+                    <p><a href="/protected/attributes">Login</a></p>
                 </body>
             </html>
         "#;
@@ -130,12 +140,13 @@ fn receive_subject(state: &mut State, attributes: UserAttributes) -> Result<(),
 }
 
 fn router() -> Router {
+    let backend = MemoryBackend::default();
     let pipelines = new_pipeline_set();
 
     let (pipelines, default) = pipelines.add(
         new_pipeline()
             .add(
-                NewSessionMiddleware::default()
+                NewSessionMiddleware::new(backend.clone())
                     .with_session_type::<Session>()
                     .insecure(),
             )
@@ -166,6 +177,10 @@ fn router() -> Router {
 
         route
             .delegate("/auth")
-            .to_router(shib_gotham::auth_router(receive_subject));
+            .to_router(shib_gotham::session_auth_router_with_config::<Session, _, _, _>(
+                receive_subject,
+                backend,
+                AuthRouterConfig::new().insecure(),
+            ));
     })
 }
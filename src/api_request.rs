@@ -0,0 +1,160 @@
+use hyper::Headers;
+
+use headers::header_value;
+
+/// Rules for recognising requests made by scripts rather than by browser navigation. A script
+/// can't usefully follow a redirect to the identity provider, so `Shibbleware` responds to these
+/// requests with `401 Unauthorized` and the login URL instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ApiRequestRules {
+    /// Requests which accept `application/json` (or another JSON type) but not `text/html`.
+    pub accept_json: bool,
+
+    /// Requests with `X-Requested-With: XMLHttpRequest`, as sent by jQuery and similar libraries.
+    pub xhr: bool,
+
+    /// Requests with `HX-Request: true`, as sent by htmx. These receive an `HX-Redirect` header, so
+    /// that htmx navigates the whole page to the login URL.
+    pub htmx: bool,
+
+    /// Requests with `Sec-Fetch-Mode: cors` or `same-origin`, as sent by browsers for `fetch` and
+    /// `XMLHttpRequest`. Other modes are left to the remaining rules, so images and scripts
+    /// (`no-cors`) and frames (`nested-navigate`) are still redirected.
+    pub fetch_mode: bool,
+}
+
+impl ApiRequestRules {
+    /// Recognises API requests by every available rule.
+    pub fn all() -> ApiRequestRules {
+        ApiRequestRules {
+            accept_json: true,
+            xhr: true,
+            htmx: true,
+            fetch_mode: true,
+        }
+    }
+
+    /// Treats every request as browser navigation, which is always redirected.
+    pub fn none() -> ApiRequestRules {
+        ApiRequestRules {
+            accept_json: false,
+            xhr: false,
+            htmx: false,
+            fetch_mode: false,
+        }
+    }
+
+    pub(crate) fn classify(&self, headers: &Headers) -> RequestKind {
+        if self.htmx && header_value(headers, "HX-Request") == Some("true") {
+            return RequestKind::Htmx;
+        }
+
+        let xhr = self.xhr
+            && header_value(headers, "X-Requested-With")
+                .map(|v| v.eq_ignore_ascii_case("XMLHttpRequest"))
+                .unwrap_or(false);
+
+        let fetch = self.fetch_mode
+            && header_value(headers, "Sec-Fetch-Mode")
+                .map(|v| v.eq_ignore_ascii_case("cors") || v.eq_ignore_ascii_case("same-origin"))
+                .unwrap_or(false);
+
+        let json = self.accept_json
+            && header_value(headers, "Accept")
+                .map(prefers_json)
+                .unwrap_or(false);
+
+        if xhr || fetch || json {
+            RequestKind::Api
+        } else {
+            RequestKind::Browser
+        }
+    }
+}
+
+impl Default for ApiRequestRules {
+    fn default() -> ApiRequestRules {
+        ApiRequestRules::all()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RequestKind {
+    Browser,
+    Api,
+    Htmx,
+}
+
+/// The body of a `401 Unauthorized` response to an API request.
+#[derive(Serialize)]
+pub(crate) struct LoginRequired<'a> {
+    pub(crate) login_url: &'a str,
+}
+
+fn prefers_json(accept: &str) -> bool {
+    let media_types = || {
+        accept
+            .split(',')
+            .map(|media_range| media_range.split(';').next().unwrap_or("").trim())
+    };
+
+    media_types().any(|t| t == "application/json" || t.ends_with("+json"))
+        && !media_types().any(|t| t == "text/html")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser_navigation() {
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", "text/html,application/xhtml+xml,*/*;q=0.8");
+        headers.set_raw("Sec-Fetch-Mode", "navigate");
+
+        assert_eq!(
+            ApiRequestRules::all().classify(&headers),
+            RequestKind::Browser
+        );
+
+        for mode in &["no-cors", "nested-navigate", "websocket"] {
+            let mut headers = Headers::new();
+            headers.set_raw("Accept", "image/webp,*/*");
+            headers.set_raw("Sec-Fetch-Mode", *mode);
+
+            assert_eq!(
+                ApiRequestRules::all().classify(&headers),
+                RequestKind::Browser
+            );
+        }
+    }
+
+    #[test]
+    fn test_api_requests() {
+        let rules = ApiRequestRules::all();
+
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", "application/json");
+        assert_eq!(rules.classify(&headers), RequestKind::Api);
+
+        let mut headers = Headers::new();
+        headers.set_raw("X-Requested-With", "XMLHttpRequest");
+        assert_eq!(rules.classify(&headers), RequestKind::Api);
+
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", "*/*");
+        headers.set_raw("Sec-Fetch-Mode", "cors");
+        assert_eq!(rules.classify(&headers), RequestKind::Api);
+
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", "*/*");
+        headers.set_raw("Sec-Fetch-Mode", "same-origin");
+        assert_eq!(rules.classify(&headers), RequestKind::Api);
+
+        let mut headers = Headers::new();
+        headers.set_raw("HX-Request", "true");
+        assert_eq!(rules.classify(&headers), RequestKind::Htmx);
+
+        assert_eq!(ApiRequestRules::none().classify(&headers), RequestKind::Browser);
+    }
+}
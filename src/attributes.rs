@@ -0,0 +1,147 @@
+//! Registry of well-known attributes released by identity providers in the Australian Access
+//! Federation, keyed by the header names `mod_shib` uses in its default `attribute-map.xml`.
+
+use std::collections::{btree_map, BTreeMap};
+
+/// A single attribute known to the registry.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AttributeDefinition {
+    /// The attribute id from `attribute-map.xml`, which is also the HTTP header name.
+    pub name: &'static str,
+
+    /// The SAML 2.0 attribute name, using the `urn:oid:` form.
+    pub oid: &'static str,
+}
+
+macro_rules! attributes {
+    ($($name:expr => $oid:expr,)*) => {
+        &[$(AttributeDefinition { name: $name, oid: concat!("urn:oid:", $oid) }),*]
+    };
+}
+
+/// The attributes known to this crate.
+pub static REGISTRY: &[AttributeDefinition] = attributes! {
+    "cn" => "2.5.4.3",
+    "sn" => "2.5.4.4",
+    "o" => "2.5.4.10",
+    "postalAddress" => "2.5.4.16",
+    "telephoneNumber" => "2.5.4.20",
+    "givenName" => "2.5.4.42",
+    "uid" => "0.9.2342.19200300.100.1.1",
+    "mail" => "0.9.2342.19200300.100.1.3",
+    "mobile" => "0.9.2342.19200300.100.1.41",
+    "displayName" => "2.16.840.1.113730.3.1.241",
+    "eduPersonAffiliation" => "1.3.6.1.4.1.5923.1.1.1.1",
+    "eduPersonNickname" => "1.3.6.1.4.1.5923.1.1.1.2",
+    "eduPersonOrgDN" => "1.3.6.1.4.1.5923.1.1.1.3",
+    "eduPersonPrimaryAffiliation" => "1.3.6.1.4.1.5923.1.1.1.5",
+    "eduPersonPrincipalName" => "1.3.6.1.4.1.5923.1.1.1.6",
+    "eduPersonEntitlement" => "1.3.6.1.4.1.5923.1.1.1.7",
+    "eduPersonScopedAffiliation" => "1.3.6.1.4.1.5923.1.1.1.9",
+    "eduPersonTargetedID" => "1.3.6.1.4.1.5923.1.1.1.10",
+    "eduPersonAssurance" => "1.3.6.1.4.1.5923.1.1.1.11",
+    "eduPersonOrcid" => "1.3.6.1.4.1.5923.1.1.1.16",
+    "schacHomeOrganization" => "1.3.6.1.4.1.25178.1.2.9",
+    "schacHomeOrganizationType" => "1.3.6.1.4.1.25178.1.2.10",
+    "auEduPersonAffiliation" => "1.3.6.1.4.1.27856.1.2.1",
+    "auEduPersonLegalName" => "1.3.6.1.4.1.27856.1.2.2",
+    "auEduPersonSharedToken" => "1.3.6.1.4.1.27856.1.2.5",
+};
+
+/// Finds the registry entry for an attribute, ignoring case in the same way as the header
+/// deserializer does.
+pub fn lookup(name: &str) -> Option<&'static AttributeDefinition> {
+    REGISTRY.iter().find(|a| a.name.eq_ignore_ascii_case(name))
+}
+
+/// Identifies which request headers carry attributes, so that ordinary HTTP headers such as
+/// `Cookie` and `Authorization` are never captured as attributes.
+///
+/// When a prefix is set, which should match the `attributePrefix` configured for `mod_shib`, only
+/// headers with that prefix are attributes and the prefix is removed from the attribute name.
+/// Otherwise, headers named in the attribute registry or the allowlist are attributes.
+#[derive(Clone, Debug)]
+pub struct AttributeFilter {
+    prefix: Option<String>,
+    registry: bool,
+    allowlist: Vec<String>,
+}
+
+impl AttributeFilter {
+    /// Creates a filter which accepts the attributes in the attribute registry.
+    pub fn new() -> AttributeFilter {
+        AttributeFilter {
+            prefix: None,
+            registry: true,
+            allowlist: Vec::new(),
+        }
+    }
+
+    /// Accepts only headers which begin with `prefix`, ignoring case.
+    pub fn with_prefix(mut self, prefix: &str) -> AttributeFilter {
+        self.prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Stops accepting attributes just because they appear in the attribute registry.
+    pub fn without_registry(mut self) -> AttributeFilter {
+        self.registry = false;
+        self
+    }
+
+    /// Accepts the attribute `name`, ignoring case, in addition to the attribute registry.
+    pub fn allow(mut self, name: &str) -> AttributeFilter {
+        self.allowlist.push(name.to_owned());
+        self
+    }
+
+    /// Returns the attribute name carried by `header`, or `None` if it isn't an attribute.
+    pub fn attribute_name<'a>(&self, header: &'a str) -> Option<&'a str> {
+        match self.prefix {
+            Some(ref prefix) => {
+                if header.len() > prefix.len() && header.is_char_boundary(prefix.len())
+                    && header[..prefix.len()].eq_ignore_ascii_case(prefix)
+                {
+                    Some(&header[prefix.len()..])
+                } else {
+                    None
+                }
+            }
+            None => {
+                let allowed = (self.registry && lookup(header).is_some())
+                    || self.allowlist.iter().any(|a| a.eq_ignore_ascii_case(header));
+
+                if allowed {
+                    Some(header)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+impl Default for AttributeFilter {
+    fn default() -> AttributeFilter {
+        AttributeFilter::new()
+    }
+}
+
+/// Every attribute received with a request, keyed by lower case attribute name.
+///
+/// Deserialize this with `deserialize_filtered_attributes` to capture all attributes without also
+/// capturing ordinary HTTP headers.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct AttributeBag(BTreeMap<String, Vec<String>>);
+
+impl AttributeBag {
+    /// Returns the values of attribute `name`, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&[String]> {
+        self.0.get(&name.to_ascii_lowercase()).map(Vec::as_slice)
+    }
+
+    /// Returns each attribute name with its values.
+    pub fn iter(&self) -> btree_map::Iter<'_, String, Vec<String>> {
+        self.0.iter()
+    }
+}
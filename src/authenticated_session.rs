@@ -1,6 +1,51 @@
 use serde::{Deserialize, Serialize};
 
+use session_state::ShibSessionState;
+
+/// A session type which records whether the user has logged in.
+///
+/// Several protections are opt-in, because they need somewhere in the session to keep their state:
+/// binding each login to the session which started it, which prevents login CSRF, passive logins,
+/// and the session requirements of `ShibblewareBuilder`, such as `session_timeout`. To enable
+/// them, keep a `ShibSessionState` in the session type and return it from `shib_state` and
+/// `shib_state_mut`:
+///
+/// ```rust,ignore
+/// #[derive(Default, Serialize, Deserialize)]
+/// struct Session {
+///     user: Option<User>,
+///     shib: ShibSessionState,
+/// }
+///
+/// impl AuthenticatedSession for Session {
+///     fn is_authenticated(&self) -> bool {
+///         self.user.is_some()
+///     }
+///
+///     fn shib_state(&self) -> Option<&ShibSessionState> {
+///         Some(&self.shib)
+///     }
+///
+///     fn shib_state_mut(&mut self) -> Option<&mut ShibSessionState> {
+///         Some(&mut self.shib)
+///     }
+/// }
+/// ```
+///
+/// Without them, `session_auth_router` and `ShibblewareBuilder::build` log a warning for each
+/// protection which is configured but can't take effect.
 pub trait AuthenticatedSession
     : Default + Serialize + for<'de> Deserialize<'de> + 'static {
     fn is_authenticated(&self) -> bool;
+
+    /// The state kept by shib-gotham in this session, if the session type provides a place for
+    /// it. Without it, logins aren't bound to the session which started them.
+    fn shib_state(&self) -> Option<&ShibSessionState> {
+        None
+    }
+
+    /// Mutable access to the state returned by `shib_state`.
+    fn shib_state_mut(&mut self) -> Option<&mut ShibSessionState> {
+        None
+    }
 }
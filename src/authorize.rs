@@ -0,0 +1,365 @@
+use std::io;
+use std::marker::PhantomData;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use futures::future;
+use hyper::{Response, StatusCode};
+
+use gotham::handler::HandlerFuture;
+use gotham::http::response::create_response;
+use gotham::middleware::session::SessionData;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{request_id, FromState, State};
+
+use authenticated_session::AuthenticatedSession;
+use middleware::SessionTypePhantom;
+
+type Predicate<T> = dyn Fn(&T) -> bool + Send + Sync + RefUnwindSafe;
+type ForbiddenResponse = dyn Fn(&State, &str) -> Response + Send + Sync + RefUnwindSafe;
+
+/// A rule which an authenticated session must satisfy to be authorized, built from predicates
+/// over the session type `T` and combined with `all` and `any`.
+///
+/// Each predicate has a name, such as `"entitlement urn:mace:aaf.edu.au:x:admin"`, which is
+/// logged when the rule fails.
+pub enum AuthorizationRule<T> {
+    /// A single predicate.
+    Predicate {
+        name: String,
+        predicate: Arc<Predicate<T>>,
+    },
+    /// Satisfied when every rule is satisfied.
+    All(Vec<AuthorizationRule<T>>),
+    /// Satisfied when any rule is satisfied.
+    Any(Vec<AuthorizationRule<T>>),
+}
+
+impl<T> AuthorizationRule<T> {
+    /// Creates a rule named `name` which is satisfied when `predicate` returns true.
+    pub fn new<F>(name: &str, predicate: F) -> AuthorizationRule<T>
+    where
+        F: Fn(&T) -> bool + Send + Sync + RefUnwindSafe + 'static,
+    {
+        AuthorizationRule::Predicate {
+            name: name.to_owned(),
+            predicate: Arc::new(predicate),
+        }
+    }
+
+    /// Creates a rule which is satisfied when every one of `rules` is satisfied.
+    pub fn all(rules: Vec<AuthorizationRule<T>>) -> AuthorizationRule<T> {
+        AuthorizationRule::All(rules)
+    }
+
+    /// Creates a rule which is satisfied when any one of `rules` is satisfied.
+    pub fn any(rules: Vec<AuthorizationRule<T>>) -> AuthorizationRule<T> {
+        AuthorizationRule::Any(rules)
+    }
+
+    /// Checks `session` against this rule, returning a description of the rule which failed.
+    pub fn check(&self, session: &T) -> Result<(), String> {
+        match *self {
+            AuthorizationRule::Predicate {
+                ref name,
+                ref predicate,
+            } => {
+                if predicate(session) {
+                    Ok(())
+                } else {
+                    Err(name.clone())
+                }
+            }
+            AuthorizationRule::All(ref rules) => {
+                rules.iter().map(|rule| rule.check(session)).collect()
+            }
+            AuthorizationRule::Any(ref rules) => {
+                let mut failures = Vec::with_capacity(rules.len());
+
+                for rule in rules {
+                    match rule.check(session) {
+                        Ok(()) => return Ok(()),
+                        Err(failure) => failures.push(failure),
+                    }
+                }
+
+                Err(format!("any of [{}]", failures.join(", ")))
+            }
+        }
+    }
+}
+
+impl<T> Clone for AuthorizationRule<T> {
+    fn clone(&self) -> Self {
+        match *self {
+            AuthorizationRule::Predicate {
+                ref name,
+                ref predicate,
+            } => AuthorizationRule::Predicate {
+                name: name.clone(),
+                predicate: predicate.clone(),
+            },
+            AuthorizationRule::All(ref rules) => AuthorizationRule::All(rules.clone()),
+            AuthorizationRule::Any(ref rules) => AuthorizationRule::Any(rules.clone()),
+        }
+    }
+}
+
+/// Gotham middleware which authorizes an authenticated session against an `AuthorizationRule`,
+/// responding with `403 Forbidden` when the rule isn't satisfied.
+///
+/// Add it to a pipeline after `Shibbleware`, which ensures the session is authenticated. A session
+/// which isn't authenticated is always forbidden.
+pub struct ShibAuthorize<T>
+where
+    T: AuthenticatedSession,
+{
+    rule: Arc<AuthorizationRule<T>>,
+    forbidden: Option<Arc<ForbiddenResponse>>,
+    phantom: PhantomData<dyn SessionTypePhantom<T>>,
+}
+
+impl<T> ShibAuthorize<T>
+where
+    T: AuthenticatedSession,
+{
+    pub fn new(rule: AuthorizationRule<T>) -> ShibAuthorize<T> {
+        ShibAuthorize {
+            rule: Arc::new(rule),
+            forbidden: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Builds the response to a forbidden request with `forbidden`, which receives the description
+    /// of the rule which failed. By default, the response is an empty `403 Forbidden`.
+    pub fn with_forbidden_response<F>(mut self, forbidden: F) -> ShibAuthorize<T>
+    where
+        F: Fn(&State, &str) -> Response + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.forbidden = Some(Arc::new(forbidden));
+        self
+    }
+}
+
+impl<T> Clone for ShibAuthorize<T>
+where
+    T: AuthenticatedSession,
+{
+    fn clone(&self) -> Self {
+        ShibAuthorize {
+            rule: self.rule.clone(),
+            forbidden: self.forbidden.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> NewMiddleware for ShibAuthorize<T>
+where
+    T: AuthenticatedSession,
+{
+    type Instance = Self;
+
+    fn new_middleware(&self) -> io::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl<T> Middleware for ShibAuthorize<T>
+where
+    T: AuthenticatedSession,
+{
+    fn call<Chain>(self, state: State, chain: Chain) -> Box<HandlerFuture>
+    where
+        Chain: FnOnce(State) -> Box<HandlerFuture>,
+    {
+        let result = {
+            let session = SessionData::<T>::borrow_from(&state);
+
+            if session.is_authenticated() {
+                self.rule.check(session)
+            } else {
+                Err("authenticated session".to_owned())
+            }
+        };
+
+        match result {
+            Ok(()) => chain(state),
+            Err(failure) => {
+                warn!(
+                    "[{}] authorization failed, session doesn't satisfy rule: {}",
+                    request_id(&state),
+                    failure
+                );
+
+                let response = match self.forbidden {
+                    Some(ref forbidden) => forbidden(&state, &failure),
+                    None => create_response(&state, StatusCode::Forbidden, None),
+                };
+
+                Box::new(future::ok((state, response)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::pipeline::new_pipeline;
+    use gotham::pipeline::set::*;
+    use gotham::router::Router;
+    use gotham::router::builder::*;
+    use gotham::middleware::session::MemoryBackend;
+    use gotham::test::TestServer;
+    use mime;
+
+    use middleware::Shibbleware;
+    use router::{session_auth_router_with_config, AuthRouterConfig};
+    use test_support::*;
+
+    /// Builds an application like `test_support::router`, with `authorize` after `Shibbleware`.
+    fn authorized_router(authorize: ShibAuthorize<TestSession>) -> Router {
+        let backend = MemoryBackend::default();
+
+        let (pipelines, default) = new_pipeline_set().add(
+            new_pipeline()
+                .add(session_middleware(backend.clone()))
+                .build(),
+        );
+        let (pipelines, protected) = pipelines.add(
+            new_pipeline()
+                .add(Shibbleware::<TestSession>::new("/auth/login"))
+                .add(authorize)
+                .build(),
+        );
+        let pipelines = finalize_pipeline_set(pipelines);
+
+        let protected_router = build_router((protected, (default, ())), pipelines.clone(), |route| {
+            route.get("/page").to(page);
+        });
+
+        build_router((default, ()), pipelines, |route| {
+            route.get("/").to(page);
+
+            route
+                .delegate_without_pipelines("/protected")
+                .to_router(protected_router);
+
+            route
+                .delegate("/auth")
+                .to_router(session_auth_router_with_config::<TestSession, TestAttributes, _, _>(
+                    receive_user,
+                    backend,
+                    AuthRouterConfig::new().insecure(),
+                ));
+        })
+    }
+
+    fn jdoe_only() -> AuthorizationRule<TestSession> {
+        AuthorizationRule::new("user == jdoe", |session: &TestSession| {
+            session.user.as_ref().map(String::as_str) == Some("jdoe")
+        })
+    }
+
+    const OTHER_USER: &[(&str, &str)] = &[
+        ("Shib-Session-ID", "_def"),
+        ("Shib-Identity-Provider", IDP),
+        ("uid", "asmith"),
+    ];
+
+    #[test]
+    fn test_shib_authorize() {
+        let server = TestServer::new(authorized_router(ShibAuthorize::new(jdoe_only()))).unwrap();
+
+        let mut browser = Browser::new(&server);
+        let response = log_in(&mut browser, "/protected/page", SP_SESSION);
+        assert_eq!(location(&response), "/protected/page");
+        assert_eq!(body(browser.get("/protected/page", &[])), "jdoe");
+
+        let mut browser = Browser::new(&server);
+        log_in(&mut browser, "/protected/page", OTHER_USER);
+        let response = browser.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::Forbidden);
+    }
+
+    #[test]
+    fn test_forbidden_response() {
+        let authorize = ShibAuthorize::new(jdoe_only()).with_forbidden_response(|state, failed| {
+            create_response(
+                state,
+                StatusCode::Forbidden,
+                Some((format!("requires {}", failed).into_bytes(), mime::TEXT_PLAIN)),
+            )
+        });
+        let server = TestServer::new(authorized_router(authorize)).unwrap();
+
+        let mut browser = Browser::new(&server);
+        log_in(&mut browser, "/protected/page", OTHER_USER);
+        let response = browser.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::Forbidden);
+        assert_eq!(body(response), "requires user == jdoe");
+    }
+
+    struct User {
+        entitlements: Vec<&'static str>,
+        affiliation: &'static str,
+        scope: &'static str,
+    }
+
+    fn rule() -> AuthorizationRule<User> {
+        AuthorizationRule::all(vec![
+            AuthorizationRule::new("scope == uni.edu.au", |u: &User| u.scope == "uni.edu.au"),
+            AuthorizationRule::any(vec![
+                AuthorizationRule::new("entitlement urn:mace:aaf.edu.au:x:admin", |u: &User| {
+                    u.entitlements.contains(&"urn:mace:aaf.edu.au:x:admin")
+                }),
+                AuthorizationRule::new("affiliation in [staff, faculty]", |u: &User| {
+                    u.affiliation == "staff" || u.affiliation == "faculty"
+                }),
+            ]),
+        ])
+    }
+
+    #[test]
+    fn test_rule_satisfied() {
+        let user = User {
+            entitlements: vec![],
+            affiliation: "faculty",
+            scope: "uni.edu.au",
+        };
+        assert_eq!(rule().check(&user), Ok(()));
+
+        let user = User {
+            entitlements: vec!["urn:mace:aaf.edu.au:x:admin"],
+            affiliation: "student",
+            scope: "uni.edu.au",
+        };
+        assert_eq!(rule().check(&user), Ok(()));
+    }
+
+    #[test]
+    fn test_rule_failures() {
+        let user = User {
+            entitlements: vec![],
+            affiliation: "staff",
+            scope: "example.edu",
+        };
+        assert_eq!(rule().check(&user), Err("scope == uni.edu.au".to_owned()));
+
+        let user = User {
+            entitlements: vec![],
+            affiliation: "student",
+            scope: "uni.edu.au",
+        };
+        assert_eq!(
+            rule().check(&user),
+            Err(
+                "any of [entitlement urn:mace:aaf.edu.au:x:admin, affiliation in [staff, faculty]]"
+                    .to_owned()
+            )
+        );
+    }
+}
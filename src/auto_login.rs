@@ -0,0 +1,325 @@
+use std::io;
+use std::marker::PhantomData;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use hyper::{Headers, Method};
+use serde::Deserialize;
+
+use gotham::handler::HandlerFuture;
+use gotham::middleware::session::SessionData;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{request_id, FromState, State};
+
+use authenticated_session::AuthenticatedSession;
+use headers::header_value;
+use middleware::{is_safe_method, SessionTypePhantom};
+use receiver::{receive_login, Receiver, ReceiverFailed};
+use router::AuthRouterConfig;
+use session_handoff::handoff_token;
+
+trait AttributesTypePhantom<A>: Send + Sync + RefUnwindSafe {}
+
+/// Gotham middleware which logs the user in on any request from an SP session, for applications
+/// which use lazy sessions (`requireSession false`), without redirecting to the login route.
+///
+/// Once the user has an SP session, mod_shib provides their attributes on every request. When a
+/// `GET` or `HEAD` request has a `Shib-Session-ID` and the application session isn't
+/// authenticated, the attributes are deserialized and passed to the receiver, as the login route
+/// would, and the request continues with the authenticated session. `Receiver::finish` isn't
+/// called. Requests with other methods aren't logged in, and are left to `Shibbleware`.
+///
+/// The session isn't moved to a new identifier, as it is by the login route, since that takes a
+/// redirect which a client that doesn't keep cookies would follow forever. Instead, the session
+/// stays bound to the SP session it was logged in from: a request for an authenticated session
+/// which doesn't come from that SP session is cleared before it continues, as
+/// `SpSessionMismatch::Invalidate` would. A session identifier planted in the user's browser
+/// before login is of no use without their SP session. This requires the session type to provide
+/// `ShibSessionState`, and a warning is logged when it doesn't.
+///
+/// When the attributes can't be deserialized or the receiver rejects them, the failure is logged
+/// and the request continues without authentication, so that the user can still browse public
+/// pages.
+///
+/// Add it to a pipeline before `Shibbleware`, with the same `AuthRouterConfig` as
+/// `session_auth_router`.
+/// The attribute headers must be cleared by mod_shib on every path it's used for, so they can't be
+/// spoofed.
+pub struct ShibAutoLogin<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    r: R,
+    config: Arc<AuthRouterConfig>,
+    phantom: PhantomData<dyn AttributesTypePhantom<A>>,
+    session_phantom: PhantomData<dyn SessionTypePhantom<T>>,
+}
+
+impl<T, A, R> ShibAutoLogin<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    pub fn new(r: R) -> Self {
+        ShibAutoLogin::with_config(r, AuthRouterConfig::default())
+    }
+
+    pub fn with_config(r: R, config: AuthRouterConfig) -> Self {
+        if T::default().shib_state().is_none() {
+            warn!(
+                "the session type doesn't provide ShibSessionState, so sessions logged in by \
+                 ShibAutoLogin aren't bound to their SP session and a planted session identifier \
+                 can be authenticated for someone else"
+            );
+        }
+
+        ShibAutoLogin {
+            r,
+            config: Arc::new(config),
+            phantom: PhantomData,
+            session_phantom: PhantomData,
+        }
+    }
+
+    /// Determines whether the request comes from an SP session which the user should be logged in
+    /// from.
+    fn has_live_sp_session(&self, state: &State) -> bool {
+        let headers = Headers::borrow_from(state);
+
+        let sp_session_id = match header_value(headers, &self.config.sp_headers.session_id) {
+            Some(sp_session_id) if !sp_session_id.is_empty() => sp_session_id,
+            _ => return false,
+        };
+
+        match self.config.sp_session_index {
+            Some(ref index) => !index.is_logged_out(sp_session_id),
+            None => true,
+        }
+    }
+}
+
+impl<T, A, R> ShibAutoLogin<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    /// Clears an authenticated session when the request doesn't come from the SP session and
+    /// identity provider which it logged in with.
+    fn check_sp_session(&self, state: &mut State) {
+        let matches = {
+            let session = SessionData::<T>::borrow_from(state);
+            let headers = Headers::borrow_from(state);
+
+            match session.shib_state() {
+                Some(shib_state) if session.is_authenticated() => shib_state.matches_sp_session(
+                    header_value(headers, &self.config.sp_headers.session_id),
+                    header_value(headers, &self.config.sp_headers.identity_provider),
+                ),
+                _ => true,
+            }
+        };
+
+        if !matches {
+            warn!(
+                "[{}] session doesn't match the SP session of the request, clearing it",
+                request_id(state)
+            );
+
+            *SessionData::<T>::borrow_mut_from(state) = T::default();
+        }
+    }
+}
+
+impl<T, A, R> Clone for ShibAutoLogin<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    fn clone(&self) -> Self {
+        ShibAutoLogin {
+            r: self.r,
+            config: self.config.clone(),
+            phantom: PhantomData,
+            session_phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, A, R> NewMiddleware for ShibAutoLogin<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    type Instance = Self;
+
+    fn new_middleware(&self) -> io::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl<T, A, R> Middleware for ShibAutoLogin<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
+    where
+        Chain: FnOnce(State) -> Box<HandlerFuture>,
+    {
+        self.check_sp_session(&mut state);
+
+        // A login received by the login route is completed by the resume route, which the
+        // browser is on its way to.
+        let resuming = handoff_token(Headers::borrow_from(&state)).is_some();
+
+        if resuming || SessionData::<T>::borrow_from(&state).is_authenticated()
+            || !self.has_live_sp_session(&state)
+            || !is_safe_method(Method::borrow_from(&state))
+        {
+            return chain(state);
+        }
+
+        // This runs on public pages too, so a login which fails leaves the user browsing
+        // anonymously rather than failing every request from the SP session.
+        match receive_login::<T, A, R>(&self.r, &self.config, &mut state) {
+            Ok(()) => info!("[{}] logged in from the SP session", request_id(&state)),
+            Err(ReceiverFailed) => warn!(
+                "[{}] failed to log in from the SP session, continuing without authentication",
+                request_id(&state)
+            ),
+        }
+
+        chain(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::middleware::session::MemoryBackend;
+    use gotham::pipeline::new_pipeline;
+    use gotham::pipeline::set::*;
+    use gotham::router::Router;
+    use gotham::router::builder::*;
+    use gotham::test::TestServer;
+    use hyper::StatusCode;
+
+    use logout_notification::SpSessionIndex;
+    use test_support::*;
+
+    fn router(config: AuthRouterConfig) -> Router {
+        let auto_login =
+            ShibAutoLogin::<TestSession, TestAttributes, _>::with_config(receive_user, config);
+
+        let (pipelines, default) = new_pipeline_set().add(
+            new_pipeline()
+                .add(session_middleware(MemoryBackend::default()))
+                .add(auto_login)
+                .build(),
+        );
+        let pipelines = finalize_pipeline_set(pipelines);
+
+        build_router((default, ()), pipelines, |route| {
+            route.get("/").to(page);
+            route.post("/").to(page);
+        })
+    }
+
+    #[test]
+    fn test_auto_login() {
+        let server = TestServer::new(router(AuthRouterConfig::new())).unwrap();
+        let mut browser = Browser::new(&server);
+
+        assert_eq!(body(browser.get("/", &[])), "anonymous");
+
+        // The request which is logged in continues with the authenticated session.
+        let response = browser.get("/?page=2", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "jdoe");
+        assert_eq!(body(browser.get("/", SP_SESSION)), "jdoe");
+
+        // A client which doesn't keep cookies is logged in on every request, without a redirect.
+        for _ in 0..2 {
+            let response = Browser::new(&server).get("/", SP_SESSION);
+            assert_eq!(response.status(), StatusCode::Ok);
+            assert_eq!(body(response), "jdoe");
+        }
+    }
+
+    #[test]
+    fn test_auto_login_binds_sp_session() {
+        let server = TestServer::new(router(AuthRouterConfig::new())).unwrap();
+
+        let mut planter = Browser::new(&server);
+        planter.get("/", &[]);
+        let planted = planter.cookie(SESSION_COOKIE).unwrap().to_owned();
+
+        let mut browser = Browser::new(&server);
+        browser.set_cookie(SESSION_COOKIE, &planted);
+        assert_eq!(body(browser.get("/", SP_SESSION)), "jdoe");
+
+        // The planted session identifier is useless without the user's SP session.
+        assert_eq!(body(planter.get("/", &[])), "anonymous");
+
+        let other = &[
+            ("Shib-Session-ID", "_def"),
+            ("Shib-Identity-Provider", IDP),
+            ("uid", "mallory"),
+        ];
+        assert_eq!(body(planter.get("/", other)), "mallory");
+    }
+
+    #[test]
+    fn test_auto_login_skipped() {
+        let index = SpSessionIndex::new();
+        let config = AuthRouterConfig::new().with_sp_session_index(index.clone());
+        let server = TestServer::new(router(config)).unwrap();
+        let mut browser = Browser::new(&server);
+
+        // Without an SP session, the request continues anonymously.
+        let response = browser.get("/", &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "anonymous");
+
+        // A form submission isn't logged in, and is left to `Shibbleware`.
+        let response = browser.post("/", "a=1", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "anonymous");
+
+        // Nor is a request from an SP session which has been logged out.
+        index.register("_abc");
+        index.logout("_abc");
+        let response = browser.get("/", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "anonymous");
+    }
+
+    #[test]
+    fn test_auto_login_failure() {
+        let server = TestServer::new(router(AuthRouterConfig::new())).unwrap();
+        let mut browser = Browser::new(&server);
+
+        let rejected = &[
+            ("Shib-Session-ID", "_abc"),
+            ("Shib-Identity-Provider", IDP),
+            ("uid", "rejected"),
+        ];
+
+        let response = browser.get("/", rejected);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "anonymous");
+
+        // Attributes which can't be deserialized are handled the same way.
+        let response = browser.get("/", &[("Shib-Session-ID", "_abc")]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "anonymous");
+    }
+}
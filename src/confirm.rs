@@ -0,0 +1,85 @@
+use futures::future;
+use gotham::handler::{Handler, HandlerFuture, NewHandler};
+use gotham::http::response::create_response;
+use gotham::state::{request_id, FromState, State};
+use hyper::{StatusCode, Uri};
+use mime;
+use std::io;
+use std::sync::Arc;
+
+use metadata::escape_xml;
+use receiver::query_parameter;
+use router::AuthRouterConfig;
+
+/// Serves a page which asks the user to confirm an action after logging in, and then resubmits it
+/// as a `POST` to the path in the `action` parameter.
+///
+/// `Shibbleware` returns users here when a browser request which started a login wasn't a `GET`,
+/// such as a sensitive action which requires recent authentication, or a form submitted after the
+/// session expired. Unless the form was kept by a `PostDataStore`, the body of the original request
+/// is lost, so the action must be identified by its path and query string alone.
+#[derive(Clone)]
+pub(crate) struct ConfirmHandler {
+    config: Arc<AuthRouterConfig>,
+}
+
+impl ConfirmHandler {
+    pub(crate) fn new(config: Arc<AuthRouterConfig>) -> ConfirmHandler {
+        ConfirmHandler { config }
+    }
+}
+
+impl NewHandler for ConfirmHandler {
+    type Instance = Self;
+
+    fn new_handler(&self) -> Result<Self, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl Handler for ConfirmHandler {
+    fn handle(self, state: State) -> Box<HandlerFuture> {
+        let action = query_parameter(Uri::borrow_from(&state).query(), "action");
+
+        // Only relative paths are resubmitted, so the page can't be used to submit a form to
+        // another site.
+        let action = match action {
+            Some(ref action)
+                if action.starts_with('/') && self.config.return_paths.is_allowed(action) =>
+            {
+                action
+            }
+            _ => {
+                warn!(
+                    "[{}] rejected confirmation of action {:?}",
+                    request_id(&state),
+                    action
+                );
+
+                let response = create_response(&state, StatusCode::BadRequest, None);
+                return Box::new(future::ok((state, response)));
+            }
+        };
+
+        let body = format!(
+            "<!DOCTYPE html>\n\
+             <html>\n\
+             <head><title>Confirm</title></head>\n\
+             <body>\n\
+             <form method=\"post\" action=\"{0}\">\n\
+             <p>You have logged in. Continue to <code>{0}</code>?</p>\n\
+             <button type=\"submit\">Continue</button>\n\
+             </form>\n\
+             </body>\n\
+             </html>\n",
+            escape_xml(action)
+        );
+
+        let response = create_response(
+            &state,
+            StatusCode::Ok,
+            Some((body.into_bytes(), mime::TEXT_HTML)),
+        );
+        Box::new(future::ok((state, response)))
+    }
+}
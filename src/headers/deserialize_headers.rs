@@ -1,18 +1,78 @@
-use std::str;
-use std::collections::BTreeMap;
+use std::borrow::Cow;
+use std::rc::Rc;
+use std::slice::Iter;
 
-use serde::de::{DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
-use hyper::header::{HeaderView, Headers};
+use serde::de::{DeserializeSeed, Deserializer, IntoDeserializer, MapAccess, SeqAccess,
+                Visitor};
+use serde::de::value::StrDeserializer;
+use hyper::header::{Headers, Raw};
 
+use attributes::AttributeFilter;
 use headers::HeadersDeserializationError;
 use headers::deserialize_values::DeserializeValue;
+use headers::field_map::FieldMap;
 
-pub(super) struct DeserializeHeaders<'a> {
+/// A single-valued header, borrowed from the request.
+pub(super) struct HeaderEntry<'a> {
+    /// The name which the header is deserialized as, which is its attribute name when filtered.
+    name: &'a str,
+    header_name: &'a str,
     headers: &'a Headers,
 }
 
-impl<'a> DeserializeHeaders<'a> {
-    pub(super) fn new(headers: &'a Headers) -> Self {
+impl<'a> HeaderEntry<'a> {
+    /// Looks up the value of the header, copying it only if it isn't valid UTF-8, as
+    /// `value_string` would.
+    ///
+    /// `HeaderView::raw` only borrows the value for as long as the view, so a value which can be
+    /// borrowed by the attributes type must be looked up by name. That is a linear search of the
+    /// headers, so it's done only for values which are deserialized. Borrowing the value through
+    /// `HeaderView::value` with a typed header instead allocates for each value, which measured
+    /// slower than the search for a typical request.
+    fn value(&self) -> Cow<'a, str> {
+        let value = self.headers
+            .get_raw(self.header_name)
+            .and_then(Raw::one)
+            .unwrap_or(b"");
+
+        String::from_utf8_lossy(value)
+    }
+}
+
+/// Collects the single-valued headers from `headers`, so that each struct in a top-level tuple can
+/// be deserialized from the same entries without walking the `Headers` again.
+///
+/// When a `filter` is provided, only attribute headers are collected, named by their attribute
+/// name.
+pub(super) fn collect_headers<'a>(
+    headers: &'a Headers,
+    filter: Option<&AttributeFilter>,
+) -> Vec<HeaderEntry<'a>> {
+    headers
+        .iter()
+        .filter(|header| header.raw().one().is_some())
+        .filter_map(|header| {
+            let header_name = header.name();
+            let name = match filter {
+                Some(filter) => filter.attribute_name(header_name)?,
+                None => header_name,
+            };
+
+            Some(HeaderEntry {
+                name,
+                header_name,
+                headers,
+            })
+        })
+        .collect()
+}
+
+pub(super) struct DeserializeHeaders<'s, 'a: 's> {
+    headers: &'s [HeaderEntry<'a>],
+}
+
+impl<'s, 'a: 's> DeserializeHeaders<'s, 'a> {
+    pub(super) fn new(headers: &'s [HeaderEntry<'a>]) -> Self {
         DeserializeHeaders { headers }
     }
 }
@@ -37,7 +97,7 @@ macro_rules! reject {
     }
 }
 
-impl<'de, 'a: 'de> Deserializer<'de> for DeserializeHeaders<'a> {
+impl<'de, 's, 'a: 'de + 's> Deserializer<'de> for DeserializeHeaders<'s, 'a> {
     type Error = HeadersDeserializationError;
 
     fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -67,19 +127,11 @@ impl<'de, 'a: 'de> Deserializer<'de> for DeserializeHeaders<'a> {
     where
         V: Visitor<'de>,
     {
-        let mappings: BTreeMap<String, &'static str> = fields.iter().cloned()
-            // Avoid copying anything which is already lower case
-            .filter_map(|a| if a.chars().any(|c| c.is_uppercase()) {
-            Some((a.to_lowercase(), a))
-        } else {
-            None
-        })
-        .collect();
-
         visitor.visit_map(AccessHeaders {
             iter: self.headers.iter(),
-            mappings,
+            fields: Some(FieldMap::for_fields(fields)),
             current: None,
+            unmatched_key: String::new(),
         })
     }
 
@@ -121,8 +173,9 @@ impl<'de, 'a: 'de> Deserializer<'de> for DeserializeHeaders<'a> {
     {
         visitor.visit_map(AccessHeaders {
             iter: self.headers.iter(),
-            mappings: BTreeMap::new(),
+            fields: None,
             current: None,
+            unmatched_key: String::new(),
         })
     }
 
@@ -223,46 +276,56 @@ impl<'de, 'a: 'de> Deserializer<'de> for DeserializeHeaders<'a> {
     );
 }
 
-struct AccessHeaders<'a, Iter>
-where
-    Iter: Iterator<Item = HeaderView<'a>> + 'a,
-{
-    iter: Iter,
-    mappings: BTreeMap<String, &'a str>,
-    current: Option<HeaderView<'a>>,
+struct AccessHeaders<'s, 'a: 's> {
+    iter: Iter<'s, HeaderEntry<'a>>,
+    /// The fields of the struct being deserialized, or `None` when deserializing a map.
+    fields: Option<Rc<FieldMap>>,
+    /// The current header, and whether it names a field of the struct or an entry of the map.
+    current: Option<(&'s HeaderEntry<'a>, bool)>,
+    /// The lower case name of the current header, when it isn't a field of the struct.
+    unmatched_key: String,
 }
 
-impl<'de, 'a: 'de, Iter> MapAccess<'de> for AccessHeaders<'a, Iter>
-where
-    Iter: Iterator<Item = HeaderView<'a>> + 'a,
-{
+impl<'de, 's, 'a: 'de + 's> MapAccess<'de> for AccessHeaders<'s, 'a> {
     type Error = HeadersDeserializationError;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
     where
         K: DeserializeSeed<'de>,
     {
-        self.current = None;
-
-        while let Some(c) = self.iter.next() {
-            if c.raw().len() == 1 {
-                self.current = Some(c);
-                break;
+        let header = match self.iter.next() {
+            Some(header) => header,
+            None => {
+                self.current = None;
+                return Ok(None);
             }
-        }
+        };
 
-        match self.current {
-            Some(ref header) => {
-                let name = header.name().to_lowercase();
-                let key = match self.mappings.get(&name) {
-                    Some(&n) => seed.deserialize(DeserializeValue::new(n)),
-                    None => seed.deserialize(DeserializeValue::new(name)),
-                };
-
-                Ok(Some(key?))
-            }
-            None => Ok(None),
-        }
+        // A header which isn't a field of the struct is named in lower case, as it was before
+        // fields were matched without regard to case, so that it still matches lower case aliases.
+        // It is lowercased into a buffer which is reused for each header. A map key is also lower
+        // case, and only copied when the header name isn't already.
+        let (key, matched) = match self.fields {
+            Some(ref fields) => match fields.get(header.name) {
+                Some(field) => (seed.deserialize(DeserializeValue::new(field)), true),
+                None => {
+                    self.unmatched_key.clear();
+                    self.unmatched_key.push_str(header.name);
+                    self.unmatched_key.make_ascii_lowercase();
+
+                    let key: StrDeserializer<HeadersDeserializationError> =
+                        self.unmatched_key.as_str().into_deserializer();
+                    (seed.deserialize(key), false)
+                }
+            },
+            None => (
+                seed.deserialize(DeserializeValue::new(lowercase(header.name))),
+                true,
+            ),
+        };
+
+        self.current = Some((header, matched));
+        Ok(Some(key?))
     }
 
     fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
@@ -270,20 +333,92 @@ where
         V: DeserializeSeed<'de>,
     {
         match self.current {
-            Some(ref header) => {
-                let deserializer = DeserializeValue::new(header.value_string());
-                Ok(seed.deserialize(deserializer)?)
+            Some((header, true)) => {
+                let value = DeserializeValue::for_attribute(header.name, header.value());
+                Ok(seed.deserialize(value)?)
             }
+            // A header which isn't a field is usually ignored by the struct, so its value is only
+            // looked up if the struct reads it, as it would for a `#[serde(alias)]`.
+            Some((header, false)) => Ok(seed.deserialize(UnmatchedValue { header })?),
             None => unreachable!("header name but no value?"),
         }
     }
 }
 
-struct TupleAccess<'a> {
-    headers: &'a Headers,
+/// The value of a header which doesn't name a field of the struct being deserialized.
+struct UnmatchedValue<'s, 'a: 's> {
+    header: &'s HeaderEntry<'a>,
+}
+
+macro_rules! look_up {
+    ($($fn:ident($($arg_i:ident : $arg_t:ty),*);)*) => {
+        $(
+            fn $fn<V>(self, $($arg_i: $arg_t,)* visitor: V) -> Result<V::Value, Self::Error>
+            where
+                V: Visitor<'de>
+            {
+                DeserializeValue::for_attribute(self.header.name, self.header.value())
+                    .$fn($($arg_i,)* visitor)
+            }
+        )*
+    }
+}
+
+impl<'de, 's, 'a: 'de + 's> Deserializer<'de> for UnmatchedValue<'s, 'a> {
+    type Error = HeadersDeserializationError;
+
+    fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    look_up! {
+        deserialize_any();
+        deserialize_bool();
+        deserialize_i8();
+        deserialize_i16();
+        deserialize_i32();
+        deserialize_i64();
+        deserialize_u8();
+        deserialize_u16();
+        deserialize_u32();
+        deserialize_u64();
+        deserialize_f32();
+        deserialize_f64();
+        deserialize_char();
+        deserialize_str();
+        deserialize_string();
+        deserialize_bytes();
+        deserialize_byte_buf();
+        deserialize_option();
+        deserialize_unit();
+        deserialize_unit_struct(name: &'static str);
+        deserialize_newtype_struct(name: &'static str);
+        deserialize_seq();
+        deserialize_tuple(len: usize);
+        deserialize_tuple_struct(name: &'static str, len: usize);
+        deserialize_map();
+        deserialize_struct(name: &'static str, fields: &'static [&'static str]);
+        deserialize_enum(name: &'static str, variants: &'static [&'static str]);
+        deserialize_identifier();
+    }
+}
+
+fn lowercase(name: &str) -> Cow<'_, str> {
+    if name.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(name.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+struct TupleAccess<'s, 'a: 's> {
+    headers: &'s [HeaderEntry<'a>],
 }
 
-impl<'de, 'a: 'de> SeqAccess<'de> for TupleAccess<'a> {
+impl<'de, 's, 'a: 'de + 's> SeqAccess<'de> for TupleAccess<'s, 'a> {
     type Error = HeadersDeserializationError;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
@@ -1,5 +1,6 @@
 use serde::de::{DeserializeSeed, Deserializer, EnumAccess, SeqAccess, VariantAccess, Visitor};
 
+use std::borrow::Cow;
 use std::error::Error;
 use std::vec::IntoIter;
 use std::ops::Deref;
@@ -11,6 +12,12 @@ pub(super) trait VisitableString<'de>: Deref<Target = str> {
     fn be_visited<V>(self, visitor: V) -> Result<V::Value, HeadersDeserializationError>
     where
         V: Visitor<'de>;
+
+    fn be_visited_as_bytes<V>(self, visitor: V) -> Result<V::Value, HeadersDeserializationError>
+    where
+        V: Visitor<'de>;
+
+    fn into_cow(self) -> Cow<'de, str>;
 }
 
 impl<'de> VisitableString<'de> for String {
@@ -20,6 +27,17 @@ impl<'de> VisitableString<'de> for String {
     {
         visitor.visit_string(self)
     }
+
+    fn be_visited_as_bytes<V>(self, visitor: V) -> Result<V::Value, HeadersDeserializationError>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_byte_buf(self.into_bytes())
+    }
+
+    fn into_cow(self) -> Cow<'de, str> {
+        Cow::Owned(self)
+    }
 }
 
 impl<'de, 'a: 'de> VisitableString<'de> for &'a str {
@@ -29,6 +47,46 @@ impl<'de, 'a: 'de> VisitableString<'de> for &'a str {
     {
         visitor.visit_borrowed_str(self)
     }
+
+    fn be_visited_as_bytes<V>(self, visitor: V) -> Result<V::Value, HeadersDeserializationError>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_borrowed_bytes(self.as_bytes())
+    }
+
+    fn into_cow(self) -> Cow<'de, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl<'de, 'a: 'de> VisitableString<'de> for Cow<'a, str> {
+    fn be_visited<V>(self, visitor: V) -> Result<V::Value, HeadersDeserializationError>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Cow::Borrowed(s) => s.be_visited(visitor),
+            Cow::Owned(s) => s.be_visited(visitor),
+        }
+    }
+
+    fn be_visited_as_bytes<V>(self, visitor: V) -> Result<V::Value, HeadersDeserializationError>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            Cow::Borrowed(s) => s.be_visited_as_bytes(visitor),
+            Cow::Owned(s) => s.be_visited_as_bytes(visitor),
+        }
+    }
+
+    fn into_cow(self) -> Cow<'de, str> {
+        match self {
+            Cow::Borrowed(s) => Cow::Borrowed(s),
+            Cow::Owned(s) => Cow::Owned(s),
+        }
+    }
 }
 
 pub(super) struct DeserializeValue<'de, S>
@@ -36,6 +94,8 @@ where
     S: VisitableString<'de>,
 {
     value: S,
+    /// The attribute which the value belongs to, for error messages.
+    attribute: Option<&'de str>,
     phantom: PhantomData<&'de str>,
 }
 
@@ -46,6 +106,16 @@ where
     pub(super) fn new(value: S) -> Self {
         DeserializeValue {
             value,
+            attribute: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Deserializes `value` as the value of the attribute `attribute`.
+    pub(super) fn for_attribute(attribute: &'de str, value: S) -> Self {
+        DeserializeValue {
+            value,
+            attribute: Some(attribute),
             phantom: PhantomData,
         }
     }
@@ -181,7 +251,7 @@ where
     where
         V: Visitor<'de>,
     {
-        visitor.visit_bytes(self.value.as_bytes())
+        self.value.be_visited_as_bytes(visitor)
     }
 
     fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -220,17 +290,34 @@ where
         visitor.visit_newtype_struct(self)
     }
 
-    reject!(
-        deserialize_tuple,
-        "unsuitable type (tuple) for attribute value",
-        (_len: usize)
-    );
+    fn deserialize_tuple<V>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let values = MultiValued::new(self.value);
+
+        if values.len() != len {
+            return Err(HeadersDeserializationError::WrongNumberOfValues {
+                attribute: self.attribute.unwrap_or("").to_owned(),
+                expected: len,
+                found: values.len(),
+            });
+        }
 
-    reject!(
-        deserialize_tuple_struct,
-        "unsuitable type (tuple struct) for attribute value",
-        (_name: &'static str, _len: usize)
-    );
+        visitor.visit_seq(values)
+    }
+
+    fn deserialize_tuple_struct<V>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_tuple(len, visitor)
+    }
 
     reject!(deserialize_map, "unsuitable type (map) for attribute value");
 
@@ -243,51 +330,73 @@ where
     reject!(deserialize_any, "unsuitable type (any) for attribute value");
 }
 
-struct MultiValued {
-    value_iter: IntoIter<String>,
+struct MultiValued<'de> {
+    value_iter: IntoIter<Cow<'de, str>>,
 }
 
-impl MultiValued {
-    fn new<'de, S>(value: S) -> Self
+impl<'de> MultiValued<'de> {
+    fn new<S>(value: S) -> Self
     where
         S: VisitableString<'de>,
     {
-        let mut curr = None;
-
-        // For an attribute which has these three values:
-        //
-        // value1\
-        // value2\
-        // value3\
-        //
-        // ... the multi-valued attribute string is represented as:
-        //
-        // value1\;value2\;value3\
-        //
-        // This is impossible to distinguish from a single attribute value of:
-        //
-        // value1;value2;value3\
-        //
-        // This is deliberate behaviour in shib-gotham to correctly handle what we get from
-        // `mod_shib`. This exact example has a test case.
-        let iter = str::split(&value, |c| {
-            let prev = curr;
-            curr = Some(c);
-
-            match prev {
-                Some('\\') => false,
-                _ => c == ';',
-            }
-        });
+        // Values are only copied when they need to be unescaped, or when the header value itself
+        // had to be copied.
+        let values: Vec<Cow<'de, str>> = match value.into_cow() {
+            Cow::Borrowed(value) => split_values(value)
+                .map(|s| {
+                    if s.contains(r"\;") {
+                        Cow::Owned(s.replace(r"\;", ";"))
+                    } else {
+                        Cow::Borrowed(s)
+                    }
+                })
+                .collect(),
+            Cow::Owned(value) => split_values(&value)
+                .map(|s| Cow::Owned(s.replace(r"\;", ";")))
+                .collect(),
+        };
 
-        let values: Vec<String> = iter.map(|s| s.replace(r"\;", ";")).collect();
         MultiValued {
             value_iter: values.into_iter(),
         }
     }
+
+    fn len(&self) -> usize {
+        self.value_iter.len()
+    }
+}
+
+fn split_values<'a>(value: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let mut curr = None;
+
+    // For an attribute which has these three values:
+    //
+    // value1\
+    // value2\
+    // value3\
+    //
+    // ... the multi-valued attribute string is represented as:
+    //
+    // value1\;value2\;value3\
+    //
+    // This is impossible to distinguish from a single attribute value of:
+    //
+    // value1;value2;value3\
+    //
+    // This is deliberate behaviour in shib-gotham to correctly handle what we get from
+    // `mod_shib`. This exact example has a test case.
+    value.split(move |c| {
+        let prev = curr;
+        curr = Some(c);
+
+        match prev {
+            Some('\\') => false,
+            _ => c == ';',
+        }
+    })
 }
 
-impl<'de> SeqAccess<'de> for MultiValued {
+impl<'de> SeqAccess<'de> for MultiValued<'de> {
     type Error = HeadersDeserializationError;
 
     fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error>
@@ -0,0 +1,61 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// The fields of a struct, arranged for case-insensitive lookup by header name.
+///
+/// Field names are bucketed by length, so most header names are rejected without comparing any
+/// characters, and the remainder are compared with `eq_ignore_ascii_case` rather than by
+/// lowercasing into a new `String`.
+pub(super) struct FieldMap {
+    by_len: Vec<Vec<&'static str>>,
+}
+
+thread_local! {
+    static FIELD_MAPS: RefCell<HashMap<(usize, usize), Rc<FieldMap>>> =
+        RefCell::new(HashMap::new());
+}
+
+impl FieldMap {
+    /// Returns the mapping for `fields`, which is built the first time a thread deserializes the
+    /// struct and reused afterwards.
+    ///
+    /// The mappings are keyed by the address and length of `fields`. Slices at the same address
+    /// with the same length have the same contents, so a key never maps to the wrong fields. The
+    /// compiler may place a copy of the same slice at more than one address, which only means that
+    /// an identical mapping is built for each copy. The mappings are dropped when the thread exits.
+    pub(super) fn for_fields(fields: &'static [&'static str]) -> Rc<FieldMap> {
+        let key = (fields.as_ptr() as usize, fields.len());
+
+        FIELD_MAPS.with(|maps| {
+            maps.borrow_mut()
+                .entry(key)
+                .or_insert_with(|| Rc::new(FieldMap::new(fields)))
+                .clone()
+        })
+    }
+
+    fn new(fields: &'static [&'static str]) -> FieldMap {
+        let mut by_len: Vec<Vec<&'static str>> = Vec::new();
+
+        for &field in fields {
+            if by_len.len() <= field.len() {
+                by_len.resize(field.len() + 1, Vec::new());
+            }
+
+            by_len[field.len()].push(field);
+        }
+
+        FieldMap { by_len }
+    }
+
+    /// Finds the field which matches `name`, ignoring ASCII case.
+    pub(super) fn get(&self, name: &str) -> Option<&'static str> {
+        self.by_len.get(name.len()).and_then(|candidates| {
+            candidates
+                .iter()
+                .cloned()
+                .find(|field| field.eq_ignore_ascii_case(name))
+        })
+    }
+}
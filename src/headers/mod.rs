@@ -1,16 +1,30 @@
 mod deserialize_headers;
 mod deserialize_values;
+mod field_map;
+mod trace_attributes;
 
 use hyper::Headers;
+use hyper::header::Raw;
 use serde::de::{self, Deserialize};
-use std::{error, fmt};
+use std::{error, fmt, str};
 
+use attributes::AttributeFilter;
+
+pub(crate) use self::trace_attributes::TracedAttribute;
+
+/// An error which occurred while deserializing user attributes from HTTP headers.
 #[derive(Debug)]
-pub(crate) enum HeadersDeserializationError {
+pub enum HeadersDeserializationError {
     InvalidTopLevelType { msg: &'static str },
     InvalidValueType { msg: &'static str },
     InvalidState { msg: &'static str },
+    MissingAttribute { name: &'static str },
     ParseError { source: &'static str, msg: String },
+    WrongNumberOfValues {
+        attribute: String,
+        expected: usize,
+        found: usize,
+    },
     GeneralError { msg: String },
 }
 
@@ -29,6 +43,10 @@ impl de::Error for HeadersDeserializationError {
             msg: format!("{}", t),
         }
     }
+
+    fn missing_field(field: &'static str) -> Self {
+        HeadersDeserializationError::MissingAttribute { name: field }
+    }
 }
 
 impl fmt::Display for HeadersDeserializationError {
@@ -37,24 +55,68 @@ impl fmt::Display for HeadersDeserializationError {
     }
 }
 
-#[allow(dead_code)]
-pub(crate) fn deserialize<T>(headers: &Headers) -> Result<T, HeadersDeserializationError>
+/// Deserializes user attributes from the headers of a request.
+///
+/// String values are borrowed from `headers` where possible, so `&str` fields and
+/// `#[serde(borrow)] Cow<str>` fields don't require a copy of the attribute value. A value in a
+/// multi-valued attribute which contains an escaped `;` must be unescaped, so it is only available
+/// as an owned value and will fail to deserialize into a `&str`.
+pub fn deserialize<'a, T>(headers: &'a Headers) -> Result<T, HeadersDeserializationError>
 where
-    for<'de> T: Deserialize<'de>,
+    T: Deserialize<'a>,
+{
+    let headers = deserialize_headers::collect_headers(headers, None);
+    let deserializer = deserialize_headers::DeserializeHeaders::new(&headers);
+    T::deserialize(deserializer)
+}
+
+/// Deserializes user attributes from the attribute headers of a request, as identified by
+/// `filter`.
+///
+/// Ordinary HTTP headers are invisible to `T`, so `#[serde(deny_unknown_fields)]` can be used, and
+/// a map or `AttributeBag` captures only attributes.
+pub fn deserialize_filtered<'a, T>(
+    headers: &'a Headers,
+    filter: &AttributeFilter,
+) -> Result<T, HeadersDeserializationError>
+where
+    T: Deserialize<'a>,
 {
-    let deserializer = deserialize_headers::DeserializeHeaders::new(headers);
+    let headers = deserialize_headers::collect_headers(headers, Some(filter));
+    let deserializer = deserialize_headers::DeserializeHeaders::new(&headers);
     T::deserialize(deserializer)
 }
 
+/// Returns the value of a single-valued header, if it is present and valid UTF-8.
+pub(crate) fn header_value<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .get_raw(name)
+        .and_then(Raw::one)
+        .and_then(|v| str::from_utf8(v).ok())
+        .map(|v| v.trim())
+}
+
+/// Lists the attributes which `T` would read from the headers of a request, without requiring any
+/// headers to be present.
+pub(crate) fn trace<T>() -> Result<Vec<TracedAttribute>, HeadersDeserializationError>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    trace_attributes::trace::<T>()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::borrow::Cow;
     use std::collections::HashMap;
 
     use hyper::Headers;
     use serde_bytes;
 
+    use attributes::AttributeBag;
+
     #[test]
     fn test_deserialize_unit() {
         let headers = Headers::new();
@@ -87,6 +149,17 @@ mod tests {
         assert_eq!(&attrs.shared_token, value);
     }
 
+    #[test]
+    fn test_missing_attribute() {
+        match deserialize::<SingleAttribute>(&Headers::new()) {
+            Err(HeadersDeserializationError::MissingAttribute {
+                name: "auEduPersonSharedToken",
+            }) => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected missing attribute to be rejected"),
+        }
+    }
+
     #[test]
     fn test_case_insensitive() {
         let value = "BuyTkNadqZW_wYOeY4ppThkRRYE";
@@ -110,6 +183,22 @@ mod tests {
         assert_eq!(&attrs.shared_token, value);
     }
 
+    #[derive(Deserialize)]
+    struct AliasedAttribute {
+        #[serde(rename = "mail", alias = "mailAddress")]
+        email: String,
+    }
+
+    #[test]
+    fn test_alias() {
+        let mut headers = Headers::new();
+        headers.set_raw("mailAddress", "john.doe@example.edu.au");
+        headers.set_raw("anotherAttribute", "unused_value");
+
+        let attrs = deserialize::<AliasedAttribute>(&headers).unwrap();
+        assert_eq!(attrs.email, "john.doe@example.edu.au");
+    }
+
     #[derive(Deserialize)]
     struct WrappedAttribute(SingleAttribute);
 
@@ -187,6 +276,41 @@ mod tests {
         assert_eq!(&attrs.entitlements[..], &[r"value1;value2;value3\"]);
     }
 
+    #[derive(Deserialize)]
+    struct ExactlyTwoValues {
+        #[serde(rename = "eduPersonEntitlement")]
+        entitlements: (String, String),
+
+        #[serde(rename = "eduPersonAffiliation")]
+        affiliations: [Affiliation; 2],
+    }
+
+    #[test]
+    fn test_tuple_value() {
+        let mut headers = Headers::new();
+        headers.set_raw("eduPersonEntitlement", r"urn:x-aaf:dev:1;value1\;value2");
+        headers.set_raw("eduPersonAffiliation", "staff;member");
+
+        let attrs = deserialize::<ExactlyTwoValues>(&headers).unwrap();
+        assert_eq!(attrs.entitlements.0, "urn:x-aaf:dev:1");
+        assert_eq!(attrs.entitlements.1, "value1;value2");
+        assert_eq!(attrs.affiliations, [Affiliation::Staff, Affiliation::Member]);
+
+        let mut headers = Headers::new();
+        headers.set_raw("eduPersonEntitlement", "urn:x-aaf:dev:1;urn:x-aaf:dev:2");
+        headers.set_raw("eduPersonAffiliation", "staff;member;employee");
+
+        match deserialize::<ExactlyTwoValues>(&headers) {
+            Err(HeadersDeserializationError::WrongNumberOfValues {
+                ref attribute,
+                expected: 2,
+                found: 3,
+            }) if attribute == "eduPersonAffiliation" => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("expected wrong number of values to be rejected"),
+        }
+    }
+
     #[derive(Deserialize)]
     struct OptionalAttribute {
         #[serde(rename = "displayName")]
@@ -297,8 +421,8 @@ mod tests {
         headers.set_raw("an_i32", "132");
         headers.set_raw("an_i64", "164");
         headers.set_raw("a_bool", "true");
-        headers.set_raw("an_f32", "3.14159265359");
-        headers.set_raw("an_f64", "2.71828182846");
+        headers.set_raw("an_f32", "3.25");
+        headers.set_raw("an_f64", "2.5");
         headers.set_raw("a_char", "\u{39e}");
 
         let attrs = deserialize::<PrimitiveValues>(&headers).unwrap();
@@ -311,9 +435,9 @@ mod tests {
         assert_eq!(attrs.an_i16, 116);
         assert_eq!(attrs.an_i32, 132);
         assert_eq!(attrs.an_i64, 164);
-        assert_eq!(attrs.a_bool, true);
-        assert_eq!(attrs.an_f32, 3.14159265359);
-        assert_eq!(attrs.an_f64, 2.71828182846);
+        assert!(attrs.a_bool);
+        assert_eq!(attrs.an_f32, 3.25);
+        assert_eq!(attrs.an_f64, 2.5);
         assert_eq!(attrs.a_char, '\u{39e}');
     }
 
@@ -369,4 +493,103 @@ mod tests {
         let _: () = attrs.unit;
         let _: NoValue = attrs.no_value;
     }
+
+    #[derive(Deserialize)]
+    struct BorrowedAttributes<'a> {
+        #[serde(rename = "auEduPersonSharedToken")]
+        shared_token: &'a str,
+
+        #[serde(borrow, rename = "displayName")]
+        display_name: Cow<'a, str>,
+
+        #[serde(borrow, rename = "eduPersonEntitlement")]
+        entitlements: Vec<Cow<'a, str>>,
+    }
+
+    #[test]
+    fn test_borrowed() {
+        let mut headers = Headers::new();
+        headers.set_raw("auEduPersonSharedToken", "BuyTkNadqZW_wYOeY4ppThkRRYE");
+        headers.set_raw("displayName", "John Doe");
+        headers.set_raw("eduPersonEntitlement", r"urn:x-aaf:dev:1;value1\;value2");
+
+        let attrs = deserialize::<BorrowedAttributes>(&headers).unwrap();
+        assert_eq!(attrs.shared_token, "BuyTkNadqZW_wYOeY4ppThkRRYE");
+
+        match attrs.display_name {
+            Cow::Borrowed(name) => assert_eq!(name, "John Doe"),
+            Cow::Owned(_) => panic!("expected display name to be borrowed"),
+        }
+
+        // serde only borrows a `Cow<str>` which is itself the field, so the values of a
+        // multi-valued attribute are always owned.
+        assert_eq!(attrs.entitlements, vec!["urn:x-aaf:dev:1", "value1;value2"]);
+    }
+
+    fn request_with_attributes() -> Headers {
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", "_gotham_session=secret");
+        headers.set_raw("Authorization", "Bearer secret");
+        headers.set_raw("Host", "sp.example.edu.au");
+        headers.set_raw("displayName", "John Doe");
+        headers.set_raw("localAttribute", "local");
+        headers.set_raw(
+            "eduPersonEntitlement",
+            "urn:x-aaf:dev:1;urn:x-aaf:dev:2;urn:x-aaf:dev:3",
+        );
+        headers
+    }
+
+    #[test]
+    fn test_attribute_bag() {
+        let headers = request_with_attributes();
+        let filter = AttributeFilter::new().allow("localattribute");
+
+        let bag = deserialize_filtered::<AttributeBag>(&headers, &filter).unwrap();
+
+        assert_eq!(bag.iter().count(), 3);
+        assert!(bag.get("Cookie").is_none());
+        assert!(bag.get("Authorization").is_none());
+        assert_eq!(bag.get("displayName").unwrap(), &["John Doe".to_owned()]);
+        assert_eq!(bag.get("localAttribute").unwrap(), &["local".to_owned()]);
+        assert_eq!(bag.get("eduPersonEntitlement").unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_attribute_prefix() {
+        let mut headers = Headers::new();
+        headers.set_raw("mail", "spoofed@example.com");
+        headers.set_raw("AJP_mail", "john.doe@example.edu.au");
+
+        let filter = AttributeFilter::new().with_prefix("AJP_");
+        let bag = deserialize_filtered::<AttributeBag>(&headers, &filter).unwrap();
+
+        assert_eq!(bag.iter().count(), 1);
+        assert_eq!(
+            bag.get("mail").unwrap(),
+            &["john.doe@example.edu.au".to_owned()]
+        );
+    }
+
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct StrictAttributes {
+        #[serde(rename = "displayName")]
+        display_name: String,
+
+        #[serde(rename = "eduPersonEntitlement")]
+        entitlements: Vec<String>,
+    }
+
+    #[test]
+    fn test_deny_unknown_fields() {
+        let headers = request_with_attributes();
+
+        assert!(deserialize::<StrictAttributes>(&headers).is_err());
+
+        let attrs =
+            deserialize_filtered::<StrictAttributes>(&headers, &AttributeFilter::new()).unwrap();
+        assert_eq!(attrs.display_name, "John Doe");
+        assert_eq!(attrs.entitlements.len(), 3);
+    }
 }
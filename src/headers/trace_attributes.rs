@@ -0,0 +1,210 @@
+use std::cell::RefCell;
+
+use serde::Deserialize;
+use serde::de::{DeserializeSeed, Deserializer, MapAccess, Visitor};
+
+use headers::HeadersDeserializationError;
+use headers::deserialize_values::DeserializeValue;
+
+/// An attribute which was requested by the type being traced.
+#[derive(Debug)]
+pub(crate) struct TracedAttribute {
+    pub(crate) name: &'static str,
+    pub(crate) required: bool,
+}
+
+/// Lists the attributes which `T` reads, from the fields which serde passes to
+/// `deserialize_struct`.
+///
+/// An attribute is optional when its field asks for an `Option<_>`. To find out, each field is
+/// deserialized in a separate pass, without a value, and the pass ends as soon as that field has
+/// been asked for its value. No values are ever supplied, so the types of the fields can't
+/// reject them.
+pub(super) fn trace<T>() -> Result<Vec<TracedAttribute>, HeadersDeserializationError>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let trace = RefCell::new(Trace {
+        fields: None,
+        target: None,
+        optional: None,
+    });
+
+    match T::deserialize(TraceHeaders { trace: &trace }) {
+        Ok(_) | Err(HeadersDeserializationError::MissingAttribute { .. }) => (),
+        Err(e) => return Err(e),
+    }
+
+    let fields = match trace.borrow().fields {
+        Some(fields) => fields,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut attributes = Vec::with_capacity(fields.len());
+
+    for (i, &name) in fields.iter().enumerate() {
+        {
+            let mut trace = trace.borrow_mut();
+            trace.target = Some(i);
+            trace.optional = None;
+        }
+
+        // The pass is expected to fail, because it stops at the field being traced.
+        let _ = T::deserialize(TraceHeaders { trace: &trace });
+
+        attributes.push(TracedAttribute {
+            name,
+            required: trace.borrow().optional != Some(true),
+        });
+    }
+
+    Ok(attributes)
+}
+
+struct Trace {
+    /// The fields of the attributes struct.
+    fields: Option<&'static [&'static str]>,
+    /// The index of the field which is traced by the current pass.
+    target: Option<usize>,
+    /// Whether the field being traced asked for an `Option<_>`, once it has asked for anything.
+    optional: Option<bool>,
+}
+
+/// Walks an attributes type, recording the fields of its struct.
+struct TraceHeaders<'t> {
+    trace: &'t RefCell<Trace>,
+}
+
+impl<'de, 't> Deserializer<'de> for TraceHeaders<'t> {
+    type Error = HeadersDeserializationError;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        Err(HeadersDeserializationError::InvalidTopLevelType {
+            msg: "requested attributes can only be derived from a struct",
+        })
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        let target = {
+            let mut trace = self.trace.borrow_mut();
+            trace.fields = Some(fields);
+            trace.target.map(|i| fields[i])
+        };
+
+        visitor.visit_map(TraceFields {
+            trace: self.trace,
+            target,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf
+        option seq tuple tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Presents the field being traced, if any, as the only header.
+struct TraceFields<'t> {
+    trace: &'t RefCell<Trace>,
+    target: Option<&'static str>,
+}
+
+impl<'de, 't> MapAccess<'de> for TraceFields<'t> {
+    type Error = HeadersDeserializationError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.target {
+            Some(field) => seed.deserialize(DeserializeValue::new(field)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        self.target = None;
+        seed.deserialize(TraceValue { trace: self.trace })
+    }
+}
+
+/// The value of the field being traced, which records whether it is asked for an `Option<_>`.
+struct TraceValue<'t> {
+    trace: &'t RefCell<Trace>,
+}
+
+impl<'t> TraceValue<'t> {
+    fn stop(self, optional: bool) -> HeadersDeserializationError {
+        self.trace.borrow_mut().optional = Some(optional);
+        HeadersDeserializationError::InvalidState {
+            msg: "attribute traced",
+        }
+    }
+}
+
+impl<'de, 't> Deserializer<'de> for TraceValue<'t> {
+    type Error = HeadersDeserializationError;
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        Err(self.stop(false))
+    }
+
+    fn deserialize_option<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        Err(self.stop(true))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf
+        unit unit_struct newtype_struct seq tuple tuple_struct map struct enum identifier
+        ignored_any
+    }
+}
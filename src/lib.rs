@@ -1,5 +1,6 @@
 //! Shibboleth SP authentication plugin for Gotham web applications
 
+extern crate chrono;
 extern crate futures;
 extern crate gotham;
 #[macro_use]
@@ -7,22 +8,62 @@ extern crate gotham_derive;
 extern crate hyper;
 #[macro_use]
 extern crate log;
+extern crate mime;
 #[macro_use]
 extern crate percent_encoding;
+extern crate rand;
+#[macro_use]
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
 
 #[cfg(test)]
 extern crate serde_bytes;
 
+mod api_request;
+pub mod attributes;
 mod authenticated_session;
-mod middleware;
-mod router;
+mod authorize;
+mod auto_login;
+mod confirm;
 mod headers;
+mod logout;
+mod logout_notification;
+mod metadata;
+mod middleware;
+mod post_data;
 mod receiver;
+mod return_path;
+mod roles;
+mod router;
+mod session_handoff;
+mod session_initiator;
+mod session_state;
+mod shib_attributes;
+mod sp_headers;
 
+#[cfg(test)]
+mod test_support;
+
+pub use api_request::ApiRequestRules;
 pub use authenticated_session::*;
+pub use authorize::{AuthorizationRule, ShibAuthorize};
+pub use auto_login::ShibAutoLogin;
+pub use headers::{deserialize as deserialize_attributes,
+                  deserialize_filtered as deserialize_filtered_attributes,
+                  HeadersDeserializationError};
+pub use logout::LogoutReceiver;
+pub use logout_notification::SpSessionIndex;
+pub use metadata::*;
 pub use middleware::*;
-pub use router::*;
+pub use post_data::{replay_preserved_post, PostDataStore};
 pub use receiver::*;
+pub use return_path::*;
+pub use roles::{RoleMapping, RoleMappingError, RoleRule};
+pub use router::*;
+pub use session_initiator::SessionInitiator;
+pub use session_state::ShibSessionState;
+pub use shib_attributes::{ShibAttributes, ShibAttributesMiddleware};
+pub use sp_headers::SpHeaders;
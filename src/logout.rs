@@ -0,0 +1,554 @@
+use futures::future;
+use gotham::handler::{Handler, HandlerFuture, NewHandler};
+use gotham::http::response::create_response;
+use gotham::middleware::session::SessionData;
+use gotham::state::{request_id, FromState, State};
+use hyper::header::Location;
+use hyper::{Headers, Method, Response, StatusCode, Uri};
+use mime;
+use percent_encoding::{percent_decode, utf8_percent_encode};
+use std::io;
+use std::marker::PhantomData;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use authenticated_session::AuthenticatedSession;
+use headers::header_value;
+use metadata::escape_xml;
+use middleware::SessionTypePhantom;
+use post_data::is_same_origin;
+use receiver::{has_session, query_parameter};
+use router::AuthRouterConfig;
+use session_initiator::TARGET_ENCODE_SET;
+use session_state::ShibSessionState;
+
+/// Clears the application's record of an authenticated user when they log out, as `Receiver`
+/// records it when they log in.
+///
+/// Without a `LogoutReceiver`, the session is replaced with the default value of the session
+/// type. In either case, the `ShibSessionState` of the session is cleared afterwards.
+pub trait LogoutReceiver: Send + Sync + RefUnwindSafe {
+    fn logout(&self, &mut State);
+}
+
+impl<F> LogoutReceiver for F
+where
+    F: Fn(&mut State) + Send + Sync + RefUnwindSafe,
+{
+    fn logout(&self, state: &mut State) {
+        self(state)
+    }
+}
+
+/// Logs the user out of the application, and then out of the SP when `AuthRouterConfig` has an SP
+/// logout handler, before redirecting to the validated `return` parameter.
+///
+/// Only a `POST` from the application's own origin logs the user out, so that another site can't
+/// log them out with a link or an image. Any other request is answered with a page which asks the
+/// user to confirm, by submitting a form which makes that `POST`.
+pub(crate) struct LogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    config: Arc<AuthRouterConfig>,
+    phantom: PhantomData<dyn SessionTypePhantom<T>>,
+}
+
+impl<T> LogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    pub(crate) fn new(config: Arc<AuthRouterConfig>) -> LogoutHandler<T> {
+        LogoutHandler {
+            config,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Reads the `return` parameter, replacing a missing or disallowed value with the default path
+    /// of the return path policy.
+    fn return_path(&self, state: &State) -> String {
+        let policy = &self.config.return_paths;
+
+        match query_parameter(Uri::borrow_from(state).query(), "return") {
+            Some(ref path) if policy.is_allowed(path) => path.clone(),
+            Some(path) => {
+                warn!(
+                    "[{}] rejected logout return path {:?}, using {:?} instead",
+                    request_id(state),
+                    path,
+                    policy.default_path()
+                );
+                policy.default_path().to_owned()
+            }
+            None => policy.default_path().to_owned(),
+        }
+    }
+
+    /// Responds with a page which asks the user to confirm that they want to log out.
+    fn confirm(&self, state: &State, return_path: &str) -> Response {
+        let action = format!(
+            "{}?return={}",
+            Uri::borrow_from(state).path(),
+            utf8_percent_encode(return_path, TARGET_ENCODE_SET)
+        );
+
+        create_response(
+            state,
+            StatusCode::Ok,
+            Some((confirm_form(&action).into_bytes(), mime::TEXT_HTML)),
+        )
+    }
+}
+
+/// Renders a page with a button which submits a `POST` to `action`.
+fn confirm_form(action: &str) -> String {
+    let mut html = String::new();
+
+    html.push_str("<!DOCTYPE html>\n<html>\n<head><title>Log out</title></head>\n<body>\n");
+    html.push_str(&format!(
+        "<form method=\"post\" action=\"{}\">\n",
+        escape_xml(action)
+    ));
+    html.push_str("<button type=\"submit\">Log out</button>\n");
+    html.push_str("</form>\n</body>\n</html>\n");
+    html
+}
+
+impl<T> Clone for LogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    fn clone(&self) -> Self {
+        LogoutHandler {
+            config: self.config.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> NewHandler for LogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> Result<Self, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl<T> Handler for LogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    fn handle(self, mut state: State) -> Box<HandlerFuture> {
+        let return_path = self.return_path(&state);
+
+        let confirmed = *Method::borrow_from(&state) == Method::Post
+            && is_same_origin(Headers::borrow_from(&state));
+
+        if !confirmed {
+            let response = self.confirm(&state, &return_path);
+            return Box::new(future::ok((state, response)));
+        }
+
+        if !has_session::<T>(&state) {
+            let response = create_response(&state, StatusCode::InternalServerError, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        clear_session::<T>(&self.config, &mut state);
+        info!("[{}] logged out", request_id(&state));
+
+        let location = match self.config.sp_logout {
+            Some(ref handler_path) => logout_url(handler_path, &return_path),
+            None => return_path,
+        };
+
+        let mut response = create_response(&state, StatusCode::SeeOther, None);
+        response.headers_mut().set(Location::new(location));
+        Box::new(future::ok((state, response)))
+    }
+}
+
+/// Clears the session with the configured `LogoutReceiver`, and then clears its
+/// `ShibSessionState`.
+fn clear_session<T>(config: &AuthRouterConfig, state: &mut State)
+where
+    T: AuthenticatedSession,
+{
+    match config.logout_receiver {
+        Some(ref logout_receiver) => logout_receiver.logout(state),
+        None => *SessionData::<T>::borrow_mut_from(state) = T::default(),
+    }
+
+    if let Some(shib_state) = SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+        *shib_state = ShibSessionState::default();
+    }
+}
+
+/// Receives front-channel logout notifications, which the SP sends by redirecting the browser
+/// with `action=logout` and a `return` URL, when configured with
+/// `<Notify Channel="front" Location="..."/>` in `shibboleth2.xml`. The session is cleared and the
+/// browser is sent back to the SP to continue logging out of other applications.
+///
+/// The notification is an ordinary `GET`, so the session is only cleared when the browser reports
+/// that it came from this site, with `Sec-Fetch-Site` or, failing that, a `Referer` on this host.
+/// Another site can't log the user out by embedding the notification URL. Otherwise, the session
+/// is left untouched, but the browser is still sent back to the SP, so that the logout continues.
+/// A browser reports a logout which the identity provider started as coming from another site, so
+/// the application session outlives it; configure back-channel notifications, or
+/// `ShibblewareBuilder::sp_session_mismatch`, to end it as well.
+pub(crate) struct FrontChannelLogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    config: Arc<AuthRouterConfig>,
+    phantom: PhantomData<dyn SessionTypePhantom<T>>,
+}
+
+impl<T> FrontChannelLogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    pub(crate) fn new(config: Arc<AuthRouterConfig>) -> FrontChannelLogoutHandler<T> {
+        FrontChannelLogoutHandler {
+            config,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> Clone for FrontChannelLogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    fn clone(&self) -> Self {
+        FrontChannelLogoutHandler {
+            config: self.config.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T> NewHandler for FrontChannelLogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> Result<Self, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl<T> Handler for FrontChannelLogoutHandler<T>
+where
+    T: AuthenticatedSession,
+{
+    fn handle(self, mut state: State) -> Box<HandlerFuture> {
+        let (action, return_url) = {
+            let query = Uri::borrow_from(&state).query();
+            (
+                query_parameter(query, "action"),
+                query_parameter(query, "return"),
+            )
+        };
+
+        let return_url = match (action.as_ref().map(String::as_str), return_url) {
+            (Some("logout"), Some(ref return_url))
+                if is_sp_return(
+                    &self.config,
+                    header_value(Headers::borrow_from(&state), "Host"),
+                    return_url,
+                ) =>
+            {
+                return_url.clone()
+            }
+            (action, return_url) => {
+                warn!(
+                    "[{}] rejected front-channel notification with action {:?} and return {:?}",
+                    request_id(&state),
+                    action,
+                    return_url
+                );
+
+                let response = create_response(&state, StatusCode::BadRequest, None);
+                return Box::new(future::ok((state, response)));
+            }
+        };
+
+        // The SP is still waiting for the browser to return, so a notification which can't be
+        // trusted leaves the session alone but continues the logout.
+        if is_same_site(Headers::borrow_from(&state)) {
+            if !has_session::<T>(&state) {
+                let response = create_response(&state, StatusCode::InternalServerError, None);
+                return Box::new(future::ok((state, response)));
+            }
+
+            clear_session::<T>(&self.config, &mut state);
+            info!("[{}] logged out by front-channel notification", request_id(&state));
+        } else {
+            warn!(
+                "[{}] ignored front-channel notification from another site, leaving the session \
+                 untouched",
+                request_id(&state)
+            );
+        }
+
+        let mut response = create_response(&state, StatusCode::SeeOther, None);
+        response.headers_mut().set(Location::new(return_url));
+        Box::new(future::ok((state, response)))
+    }
+}
+
+/// Determines whether the browser reports that a request came from this site. Browsers which send
+/// `Sec-Fetch-Site` are trusted to report it, and otherwise the `Referer` must be on this host.
+fn is_same_site(headers: &Headers) -> bool {
+    if let Some(site) = header_value(headers, "Sec-Fetch-Site") {
+        return ["same-origin", "same-site", "none"]
+            .iter()
+            .any(|allowed| site.eq_ignore_ascii_case(allowed));
+    }
+
+    let host = match header_value(headers, "Host") {
+        Some(host) => host,
+        None => return false,
+    };
+
+    header_value(headers, "Referer")
+        .and_then(|referer| referer.parse::<Uri>().ok())
+        .map_or(false, |referer| {
+            referer
+                .authority()
+                .map_or(false, |authority| authority.eq_ignore_ascii_case(host))
+        })
+}
+
+/// Determines whether a front-channel notification may return the browser to `return_url`, which
+/// must be a location beneath the SP handler, on this host or a host allowed by the return path
+/// policy.
+fn is_sp_return(config: &AuthRouterConfig, host: Option<&str>, return_url: &str) -> bool {
+    let handler_prefix = format!("{}/", config.sp_handler_path);
+
+    if return_url.starts_with('/') {
+        let path = return_url.split(|c| c == '?' || c == '#').next().unwrap_or("");
+
+        return config.return_paths.is_allowed(return_url) && is_beneath(path, &handler_prefix);
+    }
+
+    let same_host = match return_url.parse::<Uri>() {
+        Ok(uri) => match (uri.scheme(), uri.authority()) {
+            (Some(scheme), Some(authority)) => {
+                (scheme == "https" || scheme == "http") && !authority.contains('@')
+                    && host.map_or(false, |host| authority.eq_ignore_ascii_case(host))
+                    && is_beneath(uri.path(), &handler_prefix)
+            }
+            _ => false,
+        },
+        Err(_) => false,
+    };
+
+    let allowed = config.return_paths.is_allowed(return_url)
+        && return_url
+            .parse::<Uri>()
+            .map(|uri| is_beneath(uri.path(), &handler_prefix))
+            .unwrap_or(false);
+
+    same_host || allowed
+}
+
+/// Determines whether `path` is beneath `prefix` once percent-decoded, rejecting any path with a
+/// `.` or `..` segment, which could climb out of `prefix` once the browser or server resolves it.
+fn is_beneath(path: &str, prefix: &str) -> bool {
+    let path = percent_decode(path.as_bytes()).decode_utf8_lossy();
+
+    let has_dot_segments = path.split(|c| c == '/' || c == '\\')
+        .any(|segment| segment == "." || segment == "..");
+
+    !has_dot_segments && path.starts_with(prefix)
+}
+
+/// Builds the URL of the SP's logout handler, which ends the SP session, performs single logout
+/// where the identity provider supports it, and then sends the user to `return_path`.
+fn logout_url(handler_path: &str, return_path: &str) -> String {
+    format!(
+        "{}/Logout?return={}",
+        handler_path,
+        utf8_percent_encode(return_path, TARGET_ENCODE_SET)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::test::TestServer;
+
+    use middleware::Shibbleware;
+    use return_path::ReturnPathPolicy;
+    use test_support::*;
+
+    #[test]
+    fn test_logout_requires_confirmation() {
+        let server = TestServer::new(router(
+            Shibbleware::<TestSession>::new("/auth/login"),
+            AuthRouterConfig::new().insecure(),
+        )).unwrap();
+        let mut browser = Browser::new(&server);
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+
+        // A link or image from anywhere only shows the confirmation page.
+        let response = browser.get("/auth/logout?return=/goodbye", &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        let html = body(response);
+        assert!(html.contains("<form method=\"post\" action=\"/auth/logout?return=/goodbye\">"));
+        assert_eq!(body(browser.get("/", &[])), "jdoe");
+
+        // As does a form on another site.
+        let response = browser.post("/auth/logout", "", &[("Sec-Fetch-Site", "cross-site")]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(browser.get("/", &[])), "jdoe");
+
+        let response = browser.post(
+            "/auth/logout?return=/goodbye",
+            "",
+            &[("Sec-Fetch-Site", "same-origin")],
+        );
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/goodbye");
+        assert_eq!(body(browser.get("/", &[])), "anonymous");
+    }
+
+    #[test]
+    fn test_confirm_form() {
+        let html = confirm_form("/auth/logout?return=/goodbye%3Fa%3D1\"");
+
+        assert!(html.contains(
+            "<form method=\"post\" action=\"/auth/logout?return=/goodbye%3Fa%3D1&quot;\">"
+        ));
+        assert!(html.contains("<button type=\"submit\">Log out</button>"));
+    }
+
+    #[test]
+    fn test_is_same_site() {
+        fn headers(pairs: &[(&str, &str)]) -> Headers {
+            let mut headers = Headers::new();
+            for &(name, value) in pairs {
+                headers.set_raw(name.to_owned(), value.to_owned());
+            }
+            headers
+        }
+
+        let host = ("Host", "app.example.edu.au");
+
+        assert!(is_same_site(&headers(&[("Sec-Fetch-Site", "same-origin")])));
+        assert!(is_same_site(&headers(&[("Sec-Fetch-Site", "same-site")])));
+        assert!(is_same_site(&headers(&[("Sec-Fetch-Site", "none")])));
+        assert!(!is_same_site(&headers(&[
+            ("Sec-Fetch-Site", "cross-site"),
+            ("Referer", "https://app.example.edu.au/"),
+            host,
+        ])));
+
+        assert!(is_same_site(&headers(&[
+            ("Referer", "https://app.example.edu.au/Shibboleth.sso/Logout"),
+            host,
+        ])));
+        assert!(!is_same_site(&headers(&[("Referer", "https://evil.example/"), host])));
+        assert!(!is_same_site(&headers(&[host])));
+    }
+
+    #[test]
+    fn test_front_channel_logout() {
+        let server = TestServer::new(router(
+            Shibbleware::<TestSession>::new("/auth/login"),
+            AuthRouterConfig::new().insecure(),
+        )).unwrap();
+        let mut browser = Browser::new(&server);
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+
+        let notify = "/auth/notify?action=logout&return=%2FShibboleth.sso%2FLogout";
+
+        // An image or link on another site can't log the user out, but a logout which came from
+        // another site still returns to the SP.
+        let response = browser.get(notify, &[("Sec-Fetch-Site", "cross-site")]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/Shibboleth.sso/Logout");
+        let response = browser.get(notify, &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/Shibboleth.sso/Logout");
+        assert_eq!(body(browser.get("/", &[])), "jdoe");
+
+        // A return which isn't beneath the SP handler is refused, wherever the request came from.
+        let response = browser.get(
+            "/auth/notify?action=logout&return=https%3A%2F%2Fevil.example%2F",
+            &[("Sec-Fetch-Site", "cross-site")],
+        );
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        let response = browser.get(
+            "/auth/notify?action=logout&return=%2FShibboleth.sso%2F..%2Fprotected",
+            &[("Sec-Fetch-Site", "same-origin")],
+        );
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        assert_eq!(body(browser.get("/", &[])), "jdoe");
+
+        let response = browser.get(notify, &[("Sec-Fetch-Site", "same-origin")]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/Shibboleth.sso/Logout");
+        assert_eq!(body(browser.get("/", &[])), "anonymous");
+    }
+
+    #[test]
+    fn test_logout_url() {
+        assert_eq!(
+            logout_url("/Shibboleth.sso", "/goodbye?reason=logout"),
+            "/Shibboleth.sso/Logout?return=/goodbye%3Freason%3Dlogout"
+        );
+    }
+
+    #[test]
+    fn test_is_sp_return() {
+        let config = AuthRouterConfig::new();
+        let host = Some("app.example.edu.au");
+
+        assert!(is_sp_return(&config, host, "/Shibboleth.sso/Logout?notifying=1"));
+        assert!(is_sp_return(
+            &config,
+            host,
+            "https://app.example.edu.au/Shibboleth.sso/Logout?notifying=1"
+        ));
+
+        assert!(!is_sp_return(&config, host, "/protected"));
+        assert!(!is_sp_return(&config, host, "//evil.example/Shibboleth.sso/Logout"));
+        assert!(!is_sp_return(&config, host, "https://evil.example/Shibboleth.sso/Logout"));
+        assert!(!is_sp_return(
+            &config,
+            host,
+            "https://app.example.edu.au@evil.example/Shibboleth.sso/Logout"
+        ));
+        assert!(!is_sp_return(&config, None, "https://app.example.edu.au/Shibboleth.sso/Logout"));
+
+        // The path can't climb out of the SP handler.
+        assert!(!is_sp_return(&config, host, "/Shibboleth.sso/../protected"));
+        assert!(!is_sp_return(&config, host, "/Shibboleth.sso/%2e%2E/protected"));
+        assert!(!is_sp_return(&config, host, "/Shibboleth.sso/.%2e%2Fprotected"));
+        assert!(!is_sp_return(&config, host, "/Shibboleth.sso/..\\protected"));
+        assert!(!is_sp_return(
+            &config,
+            host,
+            "https://app.example.edu.au/Shibboleth.sso/./../protected"
+        ));
+        assert!(is_sp_return(&config, host, "/Shibboleth.sso/Logout?return=/a/../b"));
+
+        let config = AuthRouterConfig::new()
+            .with_return_path_policy(ReturnPathPolicy::new().allow_host("sp.example.edu.au"));
+        assert!(is_sp_return(
+            &config,
+            host,
+            "https://sp.example.edu.au/Shibboleth.sso/Logout"
+        ));
+    }
+}
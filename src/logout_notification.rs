@@ -0,0 +1,491 @@
+//! Handling of the logout notifications which the Shibboleth SP sends to applications during
+//! single logout.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future, Stream};
+use gotham::handler::{Handler, HandlerFuture, IntoHandlerError, NewHandler};
+use gotham::http::response::create_response;
+use gotham::state::{client_addr, request_id, FromState, State};
+use hyper::{self, Body, StatusCode, Uri};
+use mime;
+
+use receiver::query_parameter;
+use router::AuthRouterConfig;
+
+const NOTIFY_NAMESPACE: &str = "urn:mace:shibboleth:2.0:sp:notify";
+
+/// The largest notification body which is read, well beyond a notification naming many sessions.
+const MAX_NOTIFICATION_SIZE: usize = 64 * 1024;
+
+/// The number of SP sessions which the index holds before it first removes expired entries.
+const MIN_PRUNE_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SpSessionStatus {
+    Active,
+    LoggedOut,
+}
+
+/// An index of the SP sessions which application sessions are bound to, shared by `Shibbleware`
+/// and `session_auth_router`, so that a logout notification from the SP invalidates the application
+/// sessions of the SP sessions it names.
+///
+/// The login route records the `Shib-Session-ID` of each login in the index and in
+/// `ShibSessionState`. When the SP sends a `LogoutNotification`, the named SP sessions are marked
+/// as logged out, and `Shibbleware` clears any application session bound to one of them on its
+/// next request. Gotham's session backends can't be searched, so the application sessions are
+/// invalidated when they're next used rather than when the notification arrives.
+///
+/// Entries are forgotten after the retention period, which should be at least the `lifetime` of
+/// `<Sessions>` in `shibboleth2.xml`. The index is held in memory, so a restarted application
+/// forgets logouts which it was notified of before the restart. Notifications only mark SP
+/// sessions which were recorded at login, and are otherwise ignored.
+#[derive(Clone)]
+pub struct SpSessionIndex {
+    sessions: Arc<Mutex<Sessions>>,
+    retention: Duration,
+}
+
+struct Sessions {
+    entries: HashMap<String, (SpSessionStatus, Instant)>,
+    /// The number of entries at which expired entries are next removed, which doubles with the
+    /// entries which remain so that removing them is amortised across logins.
+    prune_len: usize,
+}
+
+impl SpSessionIndex {
+    /// Creates an empty index with the SP's default session lifetime of 8 hours as its retention
+    /// period.
+    pub fn new() -> SpSessionIndex {
+        SpSessionIndex {
+            sessions: Arc::new(Mutex::new(Sessions {
+                entries: HashMap::new(),
+                prune_len: MIN_PRUNE_LEN,
+            })),
+            retention: Duration::from_secs(8 * 60 * 60),
+        }
+    }
+
+    /// Forgets SP sessions once `retention` has passed since they were recorded.
+    pub fn with_retention(mut self, retention: Duration) -> SpSessionIndex {
+        self.retention = retention;
+        self
+    }
+
+    /// Records that an application session was bound to the SP session `sp_session_id` at login.
+    pub(crate) fn register(&self, sp_session_id: &str) {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+
+        if sessions.entries.len() >= sessions.prune_len {
+            let retention = self.retention;
+            sessions
+                .entries
+                .retain(|_, &mut (_, recorded)| now.duration_since(recorded) < retention);
+            sessions.prune_len = (sessions.entries.len() * 2).max(MIN_PRUNE_LEN);
+        }
+
+        // A logout is never undone by a later login with the same SP session, which would be a
+        // replay of an assertion from the logged out session.
+        let entry = sessions
+            .entries
+            .entry(sp_session_id.to_owned())
+            .or_insert((SpSessionStatus::Active, now));
+
+        if entry.0 != SpSessionStatus::LoggedOut {
+            *entry = (SpSessionStatus::Active, now);
+        }
+    }
+
+    /// Records that the SP session `sp_session_id` has been logged out, if it was recorded at
+    /// login. Returns false when it wasn't.
+    pub(crate) fn logout(&self, sp_session_id: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+
+        match sessions.entries.get_mut(sp_session_id) {
+            Some(entry) => {
+                *entry = (SpSessionStatus::LoggedOut, Instant::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Determines whether the SP has reported that `sp_session_id` was logged out.
+    pub(crate) fn is_logged_out(&self, sp_session_id: &str) -> bool {
+        let sessions = self.sessions.lock().unwrap_or_else(|e| e.into_inner());
+
+        match sessions.entries.get(sp_session_id) {
+            Some(&(SpSessionStatus::LoggedOut, recorded)) => recorded.elapsed() < self.retention,
+            _ => false,
+        }
+    }
+}
+
+impl Default for SpSessionIndex {
+    fn default() -> SpSessionIndex {
+        SpSessionIndex::new()
+    }
+}
+
+/// Reads the SP session IDs named by a SOAP `LogoutNotification`, such as:
+///
+/// ```xml
+/// <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+///   <s:Body>
+///     <LogoutNotification xmlns="urn:mace:shibboleth:2.0:sp:notify" type="global">
+///       <SessionID>_d5628602323819f716fcee04103ad5ef</SessionID>
+///     </LogoutNotification>
+///   </s:Body>
+/// </s:Envelope>
+/// ```
+///
+/// This is deliberately not a general XML parser. It returns `None` unless the message contains a
+/// `LogoutNotification` element.
+pub(crate) fn parse_logout_notification(message: &str) -> Option<Vec<String>> {
+    if !message.contains(NOTIFY_NAMESPACE) || elements(message, "LogoutNotification").is_empty() {
+        return None;
+    }
+
+    Some(
+        elements(message, "SessionID")
+            .into_iter()
+            .map(unescape_xml)
+            .filter(|id| !id.is_empty())
+            .collect(),
+    )
+}
+
+/// Finds the text content of each element whose local name is `local_name`, with or without a
+/// namespace prefix.
+fn elements<'a>(message: &'a str, local_name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = message;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        if tag.starts_with('/') || tag.starts_with('?') || tag.starts_with('!') {
+            continue;
+        }
+
+        let name = tag.split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        let local = name.rsplit(':').next().unwrap_or(name);
+
+        if local != local_name {
+            continue;
+        }
+
+        if tag.ends_with('/') {
+            found.push("");
+            continue;
+        }
+
+        let close = format!("</{}>", name);
+        match rest.find(&close) {
+            Some(content_end) => found.push(rest[..content_end].trim()),
+            None => break,
+        }
+    }
+
+    found
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn soap_ok() -> Vec<u8> {
+    format!(
+        "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\">\
+         <s:Body><notify:OK xmlns:notify=\"{}\"/></s:Body>\
+         </s:Envelope>",
+        NOTIFY_NAMESPACE
+    ).into_bytes()
+}
+
+/// Determines whether a logout notification may be accepted from `client`, with the query string
+/// `query`. When `AuthRouterConfig::with_notification_secret` has configured a secret, the
+/// notification must carry it in the `secret` parameter. Otherwise, it must come from loopback,
+/// and only when `AuthRouterConfig::allow_loopback` has allowed it.
+fn is_authorized(
+    config: &AuthRouterConfig,
+    client: Option<SocketAddr>,
+    query: Option<&str>,
+) -> bool {
+    match config.notification_secret {
+        Some(ref secret) => query_parameter(query, "secret")
+            .map_or(false, |given| constant_time_eq(given.as_bytes(), secret.as_bytes())),
+        None if config.loopback_notifications => {
+            client.map_or(false, |client| client.ip().is_loopback())
+        }
+        None => false,
+    }
+}
+
+/// Compares `a` and `b` in time which depends only on their lengths.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Receives back-channel `LogoutNotification` messages from the SP, which are configured with
+/// `<Notify Channel="back" Location="..."/>` in `shibboleth2.xml`.
+///
+/// Notifications are only accepted with the secret configured by
+/// `AuthRouterConfig::with_notification_secret`, or from loopback, where `shibd` usually runs,
+/// when `AuthRouterConfig::allow_loopback` allows it.
+#[derive(Clone)]
+pub(crate) struct LogoutNotificationHandler {
+    config: Arc<AuthRouterConfig>,
+}
+
+impl LogoutNotificationHandler {
+    pub(crate) fn new(config: Arc<AuthRouterConfig>) -> LogoutNotificationHandler {
+        LogoutNotificationHandler { config }
+    }
+}
+
+impl NewHandler for LogoutNotificationHandler {
+    type Instance = Self;
+
+    fn new_handler(&self) -> Result<Self, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl Handler for LogoutNotificationHandler {
+    fn handle(self, mut state: State) -> Box<HandlerFuture> {
+        let authorized = is_authorized(
+            &self.config,
+            client_addr(&state),
+            Uri::borrow_from(&state).query(),
+        );
+
+        if !authorized {
+            warn!(
+                "[{}] rejected logout notification from {:?}, which doesn't carry the \
+                 notification secret and isn't from an allowed loopback client",
+                request_id(&state),
+                client_addr(&state)
+            );
+
+            let response = create_response(&state, StatusCode::Forbidden, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        let body = Body::take_from(&mut state).fold(Vec::new(), |mut body, chunk| {
+            if body.len() + chunk.len() > MAX_NOTIFICATION_SIZE {
+                return Err(hyper::Error::TooLarge);
+            }
+
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+
+        let f = body.then(move |body| {
+            let body = match body {
+                Ok(body) => body,
+                Err(e) => return Err((state, e.into_handler_error())),
+            };
+
+            let sp_session_ids = str::from_utf8(&body)
+                .ok()
+                .and_then(parse_logout_notification);
+
+            let response = match (sp_session_ids, self.config.sp_session_index.as_ref()) {
+                (Some(mut sp_session_ids), Some(index)) => {
+                    sp_session_ids.retain(|sp_session_id| index.logout(sp_session_id));
+
+                    info!(
+                        "[{}] SP sessions logged out: {:?}",
+                        request_id(&state),
+                        sp_session_ids
+                    );
+
+                    create_response(&state, StatusCode::Ok, Some((soap_ok(), mime::TEXT_XML)))
+                }
+                (Some(_), None) => {
+                    error!(
+                        "[{}] received a logout notification, but no SpSessionIndex is configured",
+                        request_id(&state)
+                    );
+
+                    create_response(&state, StatusCode::InternalServerError, None)
+                }
+                (None, _) => {
+                    warn!("[{}] rejected malformed logout notification", request_id(&state));
+
+                    create_response(&state, StatusCode::BadRequest, None)
+                }
+            };
+
+            Ok((state, response))
+        });
+
+        Box::new(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::test::TestServer;
+
+    use middleware::Shibbleware;
+    use test_support::*;
+
+    fn notification(sp_session_id: &str) -> String {
+        format!(
+            "<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\"><s:Body>\
+             <LogoutNotification xmlns=\"{}\" type=\"global\">\
+             <SessionID>{}</SessionID></LogoutNotification></s:Body></s:Envelope>",
+            NOTIFY_NAMESPACE, sp_session_id
+        )
+    }
+
+    #[test]
+    fn test_notification_secret() {
+        let index = SpSessionIndex::new();
+        let server = TestServer::new(router(
+            Shibbleware::<TestSession>::builder()
+                .sp_session_index(index.clone())
+                .build(),
+            AuthRouterConfig::new()
+                .insecure()
+                .with_sp_session_index(index.clone())
+                .with_notification_secret("s3cret"),
+        )).unwrap();
+        let mut browser = Browser::new(&server);
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+
+        // A notification without the secret is rejected, and the session survives it.
+        let mut sp = Browser::new(&server);
+        let response = sp.post("/auth/notify", &notification("_abc"), &[]);
+        assert_eq!(response.status(), StatusCode::Forbidden);
+        let response = sp.post("/auth/notify?secret=wrong", &notification("_abc"), &[]);
+        assert_eq!(response.status(), StatusCode::Forbidden);
+        assert_eq!(body(browser.get("/protected/page", SP_SESSION)), "jdoe");
+
+        let response = sp.post("/auth/notify?secret=s3cret", &notification("_abc"), &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(index.is_logged_out("_abc"));
+
+        let response = browser.get("/protected/page", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+
+        // SP sessions which were never recorded at login aren't added to the index.
+        let response = sp.post("/auth/notify?secret=s3cret", &notification("_never"), &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(!index.is_logged_out("_never"));
+    }
+
+    #[test]
+    fn test_parse_logout_notification() {
+        let message = r#"<?xml version="1.0"?>
+            <s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/">
+              <s:Body>
+                <notify:LogoutNotification xmlns:notify="urn:mace:shibboleth:2.0:sp:notify"
+                    type="global">
+                  <notify:SessionID>_d5628602323819f716fcee04103ad5ef</notify:SessionID>
+                  <notify:SessionID> _0b2c1f4c &amp; </notify:SessionID>
+                  <notify:SessionID/>
+                </notify:LogoutNotification>
+              </s:Body>
+            </s:Envelope>"#;
+
+        assert_eq!(
+            parse_logout_notification(message),
+            Some(vec![
+                "_d5628602323819f716fcee04103ad5ef".to_owned(),
+                "_0b2c1f4c &".to_owned(),
+            ])
+        );
+
+        assert_eq!(parse_logout_notification("<SessionID>_abc</SessionID>"), None);
+        assert_eq!(parse_logout_notification("not xml"), None);
+    }
+
+    #[test]
+    fn test_sp_session_index() {
+        let index = SpSessionIndex::new();
+
+        index.register("_a");
+        index.register("_b");
+        assert!(index.logout("_a"));
+        assert!(!index.logout("_unknown"));
+
+        assert!(index.is_logged_out("_a"));
+        assert!(!index.is_logged_out("_b"));
+        assert!(!index.is_logged_out("_unknown"));
+
+        index.register("_a");
+        assert!(index.is_logged_out("_a"));
+
+        let index = SpSessionIndex::new().with_retention(Duration::from_secs(0));
+        index.register("_a");
+        index.logout("_a");
+        assert!(!index.is_logged_out("_a"));
+    }
+
+    #[test]
+    fn test_sp_session_index_pruning() {
+        let index = SpSessionIndex::new().with_retention(Duration::from_secs(0));
+
+        // Expired entries are only removed once the index reaches its threshold.
+        for i in 0..MIN_PRUNE_LEN {
+            index.register(&format!("_{}", i));
+        }
+        assert_eq!(index.sessions.lock().unwrap().entries.len(), MIN_PRUNE_LEN);
+
+        index.register("_last");
+        let sessions = index.sessions.lock().unwrap();
+        assert_eq!(sessions.entries.len(), 1);
+        assert_eq!(sessions.prune_len, MIN_PRUNE_LEN);
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let loopback = "127.0.0.1:40000".parse().ok();
+        let remote = "192.0.2.1:40000".parse().ok();
+
+        // Without a secret, nothing is accepted unless loopback is allowed.
+        let config = AuthRouterConfig::new();
+        assert!(!is_authorized(&config, loopback, None));
+        assert!(!is_authorized(&config, remote, None));
+
+        let config = AuthRouterConfig::new().allow_loopback();
+        assert!(is_authorized(&config, loopback, None));
+        assert!(is_authorized(&config, "[::1]:40000".parse().ok(), None));
+        assert!(!is_authorized(&config, remote, None));
+        assert!(!is_authorized(&config, None, None));
+
+        let config = AuthRouterConfig::new()
+            .with_notification_secret("s3cret")
+            .allow_loopback();
+        assert!(is_authorized(&config, remote, Some("secret=s3cret")));
+        assert!(!is_authorized(&config, remote, Some("secret=s3cre")));
+        assert!(!is_authorized(&config, loopback, Some("secret=wrong")));
+        assert!(!is_authorized(&config, loopback, None));
+    }
+}
@@ -0,0 +1,353 @@
+//! Generation of the requested attributes in SAML metadata, derived from the attributes type which
+//! the application receives.
+
+use std::{error, fmt};
+
+use serde::Deserialize;
+use serde_json;
+
+use attributes;
+use headers;
+
+const URI_NAME_FORMAT: &str = "urn:oasis:names:tc:SAML:2.0:attrname-format:uri";
+
+/// An error which occurred while deriving requested attributes from an attributes type.
+#[derive(Debug)]
+pub struct MetadataError {
+    msg: String,
+}
+
+impl error::Error for MetadataError {
+    fn description(&self) -> &str {
+        "unable to derive requested attributes from type"
+    }
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "unable to derive requested attributes from type: {}", self.msg)
+    }
+}
+
+/// A single attribute which the application expects to receive.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct RequestedAttribute {
+    /// The header name, which is used as the `FriendlyName` in metadata.
+    pub name: &'static str,
+
+    /// The SAML attribute name from the attribute registry, if the attribute is known.
+    pub oid: Option<&'static str>,
+
+    /// Whether the application fails to accept a session without this attribute. This is true for
+    /// every field which isn't an `Option<_>`, unless it is marked `optional`.
+    pub is_required: bool,
+}
+
+/// The attributes requested by an application, in the order they appear in the attributes type.
+#[derive(Debug, Serialize)]
+pub struct RequestedAttributes {
+    attributes: Vec<RequestedAttribute>,
+}
+
+impl RequestedAttributes {
+    /// Derives the requested attributes from the fields of `A`, as it would be deserialized by the
+    /// login route.
+    ///
+    /// The attributes are the fields which serde reports for `A`, which must be a struct or a
+    /// newtype around one. Fields of type `Option<_>` are optional, and all other fields are
+    /// required. serde doesn't reveal `#[serde(default)]`, so mark those fields with `optional`.
+    /// A field with `#[serde(alias)]` is requested under each of its names.
+    ///
+    /// Where the attributes type is a tuple, derive the requested attributes of each struct and
+    /// combine them with `merge`.
+    pub fn of<A>() -> Result<RequestedAttributes, MetadataError>
+    where
+        A: for<'de> Deserialize<'de>,
+    {
+        let traced = headers::trace::<A>().map_err(|e| MetadataError {
+            msg: format!("{}", e),
+        })?;
+
+        let attributes = traced
+            .into_iter()
+            .map(|t| RequestedAttribute {
+                name: t.name,
+                oid: attributes::lookup(t.name).map(|d| d.oid),
+                is_required: t.required,
+            })
+            .collect();
+
+        Ok(RequestedAttributes { attributes })
+    }
+
+    /// Marks the attribute `name` as optional, ignoring case, such as for a field with
+    /// `#[serde(default)]`.
+    pub fn optional(mut self, name: &str) -> RequestedAttributes {
+        for a in self.attributes
+            .iter_mut()
+            .filter(|a| a.name.eq_ignore_ascii_case(name))
+        {
+            a.is_required = false;
+        }
+
+        self
+    }
+
+    /// Adds the attributes requested by `other`. An attribute requested by both is required if
+    /// either requires it.
+    pub fn merge(mut self, other: RequestedAttributes) -> RequestedAttributes {
+        for a in other.attributes {
+            if let Some(existing) = self.attributes
+                .iter_mut()
+                .find(|e| e.name.eq_ignore_ascii_case(a.name))
+            {
+                existing.is_required |= a.is_required;
+                continue;
+            }
+
+            self.attributes.push(a);
+        }
+
+        self
+    }
+
+    /// The requested attributes.
+    pub fn iter(&self) -> ::std::slice::Iter<'_, RequestedAttribute> {
+        self.attributes.iter()
+    }
+
+    /// Renders an `<md:AttributeConsumingService>` element for inclusion in SP metadata.
+    ///
+    /// Attributes which aren't in the attribute registry have no SAML name, so they are left out
+    /// with a warning.
+    pub fn to_xml(&self, service_name: &str) -> String {
+        let mut xml = String::new();
+
+        xml.push_str("<md:AttributeConsumingService index=\"1\">\n");
+        xml.push_str(&format!(
+            "  <md:ServiceName xml:lang=\"en\">{}</md:ServiceName>\n",
+            escape_xml(service_name)
+        ));
+
+        for a in &self.attributes {
+            match a.oid {
+                Some(oid) => xml.push_str(&format!(
+                    "  <md:RequestedAttribute FriendlyName=\"{}\" Name=\"{}\" NameFormat=\"{}\" \
+                     isRequired=\"{}\"/>\n",
+                    escape_xml(a.name),
+                    oid,
+                    URI_NAME_FORMAT,
+                    a.is_required
+                )),
+                None => warn!(
+                    "attribute {} is not in the attribute registry, omitting it from metadata",
+                    a.name
+                ),
+            }
+        }
+
+        xml.push_str("</md:AttributeConsumingService>\n");
+        xml
+    }
+
+    /// Renders the requested attributes as a JSON summary.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("requested attributes are always serializable")
+    }
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::de::{self, Deserializer};
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    #[allow(dead_code)]
+    enum Affiliation {
+        Staff,
+        Student,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct UserAttributes {
+        #[serde(rename = "auEduPersonSharedToken")]
+        shared_token: String,
+
+        #[serde(rename = "displayName")]
+        display_name: Option<String>,
+
+        #[serde(rename = "eduPersonEntitlement")]
+        entitlements: Vec<String>,
+
+        #[serde(rename = "eduPersonAffiliation")]
+        affiliation: Affiliation,
+
+        #[serde(rename = "localThing")]
+        local: Option<u32>,
+    }
+
+    #[test]
+    fn test_requested_attributes() {
+        let requested = RequestedAttributes::of::<UserAttributes>().unwrap();
+
+        let summary: Vec<(&str, bool)> = requested
+            .iter()
+            .map(|a| (a.name, a.is_required))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("auEduPersonSharedToken", true),
+                ("displayName", false),
+                ("eduPersonEntitlement", true),
+                ("eduPersonAffiliation", true),
+                ("localThing", false),
+            ]
+        );
+
+        let token = requested.iter().next().unwrap();
+        assert_eq!(token.oid, Some("urn:oid:1.3.6.1.4.1.27856.1.2.5"));
+    }
+
+    #[derive(Debug)]
+    struct Email(String);
+
+    impl<'de> Deserialize<'de> for Email {
+        fn deserialize<D>(deserializer: D) -> Result<Email, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            let email = String::deserialize(deserializer)?;
+
+            if email.contains('@') {
+                Ok(Email(email))
+            } else {
+                Err(de::Error::custom("not an email address"))
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct ValidatedAttributes {
+        #[serde(rename = "displayName", default)]
+        display_name: String,
+
+        #[serde(rename = "givenName")]
+        given_name: Option<String>,
+
+        #[serde(rename = "mail")]
+        email: Email,
+
+        #[serde(rename = "sn", default)]
+        surname: String,
+    }
+
+    #[test]
+    fn test_default_and_validated_attributes() {
+        let requested = RequestedAttributes::of::<ValidatedAttributes>()
+            .unwrap()
+            .optional("displayname")
+            .optional("sn");
+
+        let summary: Vec<(&str, bool)> = requested
+            .iter()
+            .map(|a| (a.name, a.is_required))
+            .collect();
+
+        assert_eq!(
+            summary,
+            vec![
+                ("displayName", false),
+                ("givenName", false),
+                ("mail", true),
+                ("sn", false),
+            ]
+        );
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Wrapped(ValidatedAttributes);
+
+    #[test]
+    fn test_newtype() {
+        let requested = RequestedAttributes::of::<Wrapped>().unwrap();
+        let names: Vec<&str> = requested.iter().map(|a| a.name).collect();
+
+        assert_eq!(names, vec!["displayName", "givenName", "mail", "sn"]);
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct OptionalName {
+        #[serde(rename = "displayName")]
+        display_name: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct RequiredName {
+        #[serde(rename = "displayname")]
+        display_name: String,
+    }
+
+    #[test]
+    fn test_merge() {
+        let requested = RequestedAttributes::of::<OptionalName>()
+            .unwrap()
+            .merge(RequestedAttributes::of::<RequiredName>().unwrap());
+
+        let attributes: Vec<&RequestedAttribute> = requested.iter().collect();
+        assert_eq!(attributes.len(), 1);
+        assert!(attributes[0].is_required);
+    }
+
+    #[test]
+    fn test_tuple_rejected() {
+        assert!(RequestedAttributes::of::<(OptionalName, RequiredName)>().is_err());
+    }
+
+    #[test]
+    fn test_map_rejected() {
+        use std::collections::HashMap;
+
+        assert!(RequestedAttributes::of::<HashMap<String, String>>().is_err());
+    }
+
+    #[test]
+    fn test_xml() {
+        let xml = RequestedAttributes::of::<UserAttributes>()
+            .unwrap()
+            .to_xml("Example & Co");
+
+        assert!(xml.contains("<md:ServiceName xml:lang=\"en\">Example &amp; Co</md:ServiceName>"));
+        assert!(xml.contains(
+            "<md:RequestedAttribute FriendlyName=\"displayName\" \
+             Name=\"urn:oid:2.16.840.1.113730.3.1.241\" \
+             NameFormat=\"urn:oasis:names:tc:SAML:2.0:attrname-format:uri\" isRequired=\"false\"/>"
+        ));
+        assert!(!xml.contains("localThing"));
+    }
+}
@@ -1,32 +1,214 @@
 use std::io;
 use std::marker::PhantomData;
 use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::future;
+use futures::{future, Future, Stream};
 use hyper::header::Location;
-use hyper::{StatusCode, Uri};
+use hyper::{self, Body, Headers, Method, Response, StatusCode, Uri};
+use mime;
 use percent_encoding::{utf8_percent_encode, QUERY_ENCODE_SET};
+use serde_json;
 
-use gotham::handler::HandlerFuture;
+use gotham::handler::{HandlerFuture, IntoHandlerError};
 use gotham::http::response::create_response;
 use gotham::middleware::session::SessionData;
 use gotham::middleware::{Middleware, NewMiddleware};
-use gotham::state::{FromState, State};
+use gotham::state::{request_id, FromState, State};
 
+use api_request::{ApiRequestRules, LoginRequired, RequestKind};
 use authenticated_session::AuthenticatedSession;
+use headers::header_value;
+use logout_notification::SpSessionIndex;
+use post_data::{is_preservable, is_same_origin, PostDataStore, PreservedPost};
+use session_initiator::SessionInitiator;
+use session_state::{authn_context_satisfied, random_nonce, PendingLogin};
+use sp_headers::SpHeaders;
 
-trait SessionTypePhantom<T>: Send + Sync + RefUnwindSafe
+/// How old the recorded time of the last authenticated request must be before it's recorded
+/// again, which also bounds how early the idle timeout can expire a session.
+const TOUCH_GRANULARITY: Duration = Duration::from_secs(60);
+
+pub(crate) trait SessionTypePhantom<T>: Send + Sync + RefUnwindSafe
 where
     T: Send,
 {
 }
 
+/// The status code used to redirect an unauthenticated browser to the login route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RedirectStatus {
+    /// `302 Found`
+    Found,
+    /// `303 See Other`, the default.
+    SeeOther,
+    /// `307 Temporary Redirect`, which makes the browser repeat the request's method and body.
+    /// The login route only accepts `GET`, so requests with any other method are redirected with
+    /// `303 See Other` instead.
+    TemporaryRedirect,
+}
+
+impl RedirectStatus {
+    fn status_code(&self) -> StatusCode {
+        match *self {
+            RedirectStatus::Found => StatusCode::Found,
+            RedirectStatus::SeeOther => StatusCode::SeeOther,
+            RedirectStatus::TemporaryRedirect => StatusCode::TemporaryRedirect,
+        }
+    }
+}
+
+/// What `Shibbleware` does with an authenticated session when the request doesn't come from the SP
+/// session and identity provider which the session logged in with. See
+/// `ShibblewareBuilder::sp_session_mismatch`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpSessionMismatch {
+    /// Don't compare the SP session, the default.
+    Ignore,
+    /// Clear the session, so the request proceeds as though the user had never logged in.
+    Invalidate,
+    /// Start a login, so the receiver runs again with the attributes of the current SP session.
+    /// The session keeps its contents until the login is received.
+    Login,
+}
+
+/// What `Shibbleware` does with a request whose method it lets through without authentication.
+/// See `ShibblewareBuilder::bypass_method`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MethodBypass {
+    /// Pass the request on to the rest of the pipeline.
+    Chain,
+    /// Respond with `204 No Content`, without calling the rest of the pipeline.
+    NoContent,
+}
+
+/// How a path given to `ShibblewareBuilder::exclude_path` matches the paths of requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PathMatch {
+    /// Matches the path and any path beneath it, so `/static` matches `/static/app.css` but not
+    /// `/static-assets`.
+    Segments,
+    /// Matches any path which starts with the path, whether or not it ends at a path segment, so
+    /// `/favicon` matches `/favicon.ico` and `/favicon-32x32.png`.
+    Prefix,
+}
+
+type BypassPredicate = dyn Fn(&State) -> bool + Send + Sync + RefUnwindSafe;
+
+#[derive(Clone)]
+struct ShibblewareConfig {
+    login_location: Arc<str>,
+    return_parameter: Arc<str>,
+    redirect_status: RedirectStatus,
+    excluded_paths: Vec<(Arc<str>, PathMatch)>,
+    bypass_methods: Vec<(Method, MethodBypass)>,
+    bypass_predicates: Vec<Arc<BypassPredicate>>,
+    api_request_rules: ApiRequestRules,
+    session_initiator: Option<SessionInitiator>,
+    passive: bool,
+    required_authn_contexts: Vec<String>,
+    max_authentication_age: Option<Duration>,
+    confirm_location: Arc<str>,
+    session_lifetime: Option<Duration>,
+    session_timeout: Option<Duration>,
+    sp_session_index: Option<SpSessionIndex>,
+    sp_session_mismatch: SpSessionMismatch,
+    sp_headers: SpHeaders,
+    post_data_store: Option<PostDataStore>,
+}
+
+/// Options for a single login started by `Shibbleware`.
+#[derive(Default)]
+struct LoginOptions {
+    /// Authenticate without interacting with the user, falling back to an anonymous session.
+    passive: bool,
+    /// Request one of these authentication context classes from the identity provider.
+    required_authn_contexts: Vec<String>,
+    /// Require the identity provider to authenticate the user again.
+    force_authn: bool,
+    /// The maximum age of the authentication which the login must carry.
+    max_authentication_age: Option<Duration>,
+    /// The nonce of the form submission which this login interrupted, in the `PostDataStore`.
+    post_data: Option<String>,
+}
+
+impl ShibblewareConfig {
+    /// Whether this middleware requires more of a session than that it is authenticated.
+    fn has_session_requirements(&self) -> bool {
+        !self.required_authn_contexts.is_empty() || self.max_authentication_age.is_some()
+            || self.session_lifetime.is_some() || self.session_timeout.is_some()
+            || self.sp_session_mismatch != SpSessionMismatch::Ignore
+    }
+
+    /// The configured settings which have no effect unless the session type provides
+    /// `ShibSessionState`.
+    fn settings_requiring_state(&self) -> Vec<&'static str> {
+        let settings = [
+            ("passive", self.passive),
+            ("require_authn_context", !self.required_authn_contexts.is_empty()),
+            (
+                "require_recent_authentication",
+                self.max_authentication_age.is_some(),
+            ),
+            ("session_lifetime", self.session_lifetime.is_some()),
+            ("session_timeout", self.session_timeout.is_some()),
+            ("sp_session_index", self.sp_session_index.is_some()),
+            (
+                "sp_session_mismatch",
+                self.sp_session_mismatch != SpSessionMismatch::Ignore,
+            ),
+            ("post_data_store", self.post_data_store.is_some()),
+        ];
+
+        settings
+            .iter()
+            .filter(|&&(_, configured)| configured)
+            .map(|&(setting, _)| setting)
+            .collect()
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excluded_paths
+            .iter()
+            .any(|&(ref excluded, matching)| {
+                path.starts_with(&**excluded) && match matching {
+                    PathMatch::Segments => {
+                        excluded.ends_with('/') || path.len() == excluded.len()
+                            || path[excluded.len()..].starts_with('/')
+                    }
+                    PathMatch::Prefix => true,
+                }
+            })
+    }
+
+    /// Determines whether the request is let through without authentication, and how.
+    fn bypass(&self, state: &State) -> Option<MethodBypass> {
+        let method = Method::borrow_from(state);
+
+        if let Some(&(_, bypass)) = self.bypass_methods.iter().find(|&&(ref m, _)| m == method) {
+            return Some(bypass);
+        }
+
+        if self.is_excluded(Uri::borrow_from(state).path())
+            || self.bypass_predicates.iter().any(|predicate| predicate(state))
+        {
+            return Some(MethodBypass::Chain);
+        }
+
+        None
+    }
+}
+
 /// Gotham middleware for receiving Shibboleth attributes and mapping them into a provided type.
+///
+/// Use `Shibbleware::new` for a fixed login location, or `Shibbleware::builder` when the
+/// configuration comes from a file or the environment.
 pub struct Shibbleware<T>
 where
     T: AuthenticatedSession,
 {
-    auth_login_location: &'static str,
+    config: Arc<ShibblewareConfig>,
     phantom: PhantomData<dyn SessionTypePhantom<T>>,
 }
 
@@ -35,21 +217,660 @@ where
     T: AuthenticatedSession,
 {
     pub fn new(auth_login_location: &'static str) -> Shibbleware<T> {
-        Shibbleware {
-            auth_login_location,
+        Shibbleware::builder()
+            .login_location(auth_login_location)
+            .build()
+    }
+
+    /// Creates a `Shibbleware` for routes which require the user to have authenticated with the
+    /// context class `authn_context_class`. See `ShibblewareBuilder::require_authn_context`.
+    pub fn require_authn_context(authn_context_class: &str) -> Shibbleware<T> {
+        Shibbleware::builder()
+            .require_authn_context(authn_context_class)
+            .build()
+    }
+
+    /// Begins configuring a `Shibbleware`, for configuration which is only known at runtime.
+    pub fn builder() -> ShibblewareBuilder<T> {
+        ShibblewareBuilder {
+            config: ShibblewareConfig {
+                login_location: Arc::from("/auth/login"),
+                return_parameter: Arc::from("return_path"),
+                redirect_status: RedirectStatus::SeeOther,
+                excluded_paths: Vec::new(),
+                bypass_methods: Vec::new(),
+                bypass_predicates: Vec::new(),
+                api_request_rules: ApiRequestRules::default(),
+                session_initiator: None,
+                passive: false,
+                required_authn_contexts: Vec::new(),
+                max_authentication_age: None,
+                confirm_location: Arc::from("/auth/confirm"),
+                session_lifetime: None,
+                session_timeout: None,
+                sp_session_index: None,
+                sp_session_mismatch: SpSessionMismatch::Ignore,
+                sp_headers: SpHeaders::new(),
+                post_data_store: None,
+            },
             phantom: PhantomData,
         }
     }
+
+    /// Recognises API requests using `rules`. Unauthenticated API requests receive
+    /// `401 Unauthorized` with the login URL, rather than a redirect. By default, every rule is
+    /// enabled.
+    pub fn with_api_request_rules(mut self, rules: ApiRequestRules) -> Shibbleware<T> {
+        Arc::make_mut(&mut self.config).api_request_rules = rules;
+        self
+    }
+
+    /// Responds to an unauthenticated request by starting a login, with a redirect for browsers
+    /// or a `401 Unauthorized` for scripts.
+    fn login_required(
+        &self,
+        state: &mut State,
+        kind: RequestKind,
+        options: LoginOptions,
+    ) -> Response {
+        let location = self.start_login(state, kind, &options);
+
+        match kind {
+            RequestKind::Browser => {
+                // A `307` would repeat a `POST` to the login route, which only accepts `GET`.
+                let status = if is_safe_method(Method::borrow_from(state)) {
+                    self.config.redirect_status.status_code()
+                } else {
+                    StatusCode::SeeOther
+                };
+
+                let mut response = create_response(state, status, None);
+                response.headers_mut().set(Location::new(location));
+                response
+            }
+            RequestKind::Api => {
+                let body = serde_json::to_vec(&LoginRequired {
+                    login_url: &location,
+                }).expect("login required body is always serializable");
+
+                create_response(
+                    state,
+                    StatusCode::Unauthorized,
+                    Some((body, mime::APPLICATION_JSON)),
+                )
+            }
+            RequestKind::Htmx => {
+                let mut response = create_response(state, StatusCode::Unauthorized, None);
+                response.headers_mut().set_raw("HX-Redirect", location);
+                response
+            }
+        }
+    }
+
+    /// Records a pending login in the session, if the session can hold one, and returns the URL
+    /// which the user must visit to log in. This is the login route, or the SP's SessionInitiator
+    /// with the login route as its target.
+    ///
+    /// An API request reuses a login which is already pending, so that a burst of requests from a
+    /// script doesn't invalidate the login URL given to the earlier requests.
+    fn start_login(&self, state: &mut State, kind: RequestKind, options: &LoginOptions) -> String {
+        let login_location = self.login_route_location(state, kind, options);
+
+        // Passive and step-up logins can only be requested through the SessionInitiator.
+        let needs_session_initiator =
+            options.passive || options.force_authn || !options.required_authn_contexts.is_empty();
+
+        let session_initiator = match self.config.session_initiator {
+            Some(ref session_initiator) => Some(session_initiator.clone()),
+            None if needs_session_initiator => Some(SessionInitiator::new()),
+            None => None,
+        };
+
+        match session_initiator {
+            Some(mut session_initiator) => {
+                if options.passive {
+                    session_initiator = session_initiator.is_passive(true);
+                }
+
+                if options.force_authn {
+                    session_initiator = session_initiator.force_authn(true);
+                }
+
+                if !options.required_authn_contexts.is_empty() {
+                    session_initiator = session_initiator
+                        .authn_context_class_ref(&options.required_authn_contexts.join(" "));
+                }
+
+                session_initiator.url(&login_location)
+            }
+            None => login_location,
+        }
+    }
+
+    fn login_route_location(
+        &self,
+        state: &mut State,
+        kind: RequestKind,
+        options: &LoginOptions,
+    ) -> String {
+        let mut return_path = {
+            let uri = Uri::borrow_from(state);
+
+            match uri.query() {
+                Some(query) => format!("{}?{}", uri.path(), query),
+                None => uri.path().to_owned(),
+            }
+        };
+
+        // Returning to a form submission which wasn't preserved would turn it into a `GET`, so the
+        // user returns to a page which asks them to confirm the action instead.
+        if kind == RequestKind::Browser && options.post_data.is_none()
+            && !is_safe_method(Method::borrow_from(state))
+        {
+            return_path = format!(
+                "{}?action={}",
+                self.config.confirm_location,
+                utf8_percent_encode(&return_path, QUERY_VALUE_ENCODE_SET)
+            );
+        }
+
+        // When the session can hold it, the return path is kept in the session alongside a
+        // nonce, and only the nonce is sent to the login route. The login route accepts the login
+        // only when the nonce matches, so a login can't be forced on a user and the return path
+        // can't be tampered with.
+        match SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+            Some(shib_state) => {
+                let max_authentication_age =
+                    options.max_authentication_age.map(|age| age.as_secs());
+
+                let reusable = shib_state.pending_logins.iter().rev().find(|pending| {
+                    kind != RequestKind::Browser && pending.passive == options.passive
+                        && pending.required_authn_contexts == options.required_authn_contexts
+                        && pending.max_authentication_age == max_authentication_age
+                });
+
+                let nonce = match reusable {
+                    Some(pending) => pending.nonce.clone(),
+                    None => {
+                        let nonce = random_nonce();
+                        shib_state.add_pending_login(PendingLogin {
+                            nonce: nonce.clone(),
+                            return_path,
+                            passive: options.passive,
+                            required_authn_contexts: options.required_authn_contexts.clone(),
+                            max_authentication_age,
+                            post_data: options.post_data.clone(),
+                        });
+                        nonce
+                    }
+                };
+
+                format!("{}?state={}", self.config.login_location, nonce)
+            }
+            None => {
+                let encoded_return_path = utf8_percent_encode(&return_path, QUERY_VALUE_ENCODE_SET);
+
+                format!(
+                    "{}?{}={}",
+                    self.config.login_location, self.config.return_parameter, encoded_return_path
+                )
+            }
+        }
+    }
+
+    /// Checks the session against the lifetime, authentication context class and freshness
+    /// required by this middleware, and returns the options for a login which would satisfy them.
+    /// Returns `None` when the session is authenticated and satisfies every requirement.
+    fn required_login(&self, state: &State, authenticated: bool) -> Option<LoginOptions> {
+        let session = SessionData::<T>::borrow_from(state);
+        let shib_state = session.shib_state();
+
+        let context_satisfied = authn_context_satisfied(
+            &self.config.required_authn_contexts,
+            shib_state.and_then(|shib_state| shib_state.authn_context_class()),
+        );
+
+        let recent = match self.config.max_authentication_age {
+            Some(max_age) => shib_state.map_or(false, |shib_state| {
+                shib_state.authenticated_within(max_age)
+            }),
+            None => true,
+        };
+
+        let expired = shib_state.map_or(false, |shib_state| {
+            shib_state.is_expired(self.config.session_lifetime, self.config.session_timeout)
+        });
+
+        if authenticated && !expired && context_satisfied && recent {
+            return None;
+        }
+
+        Some(LoginOptions {
+            passive: false,
+            required_authn_contexts: self.config.required_authn_contexts.clone(),
+            force_authn: !recent,
+            max_authentication_age: self.config.max_authentication_age,
+            ..LoginOptions::default()
+        })
+    }
+
+    /// Records that the user made an authenticated request, for the idle timeout. The session is
+    /// only written when the recorded time is a minute old, so that most requests don't write the
+    /// session to the backend.
+    fn touch_session(&self, state: &mut State) {
+        let stale = SessionData::<T>::borrow_from(state)
+            .shib_state()
+            .map_or(false, |shib_state| shib_state.is_stale(TOUCH_GRANULARITY));
+
+        if stale {
+            if let Some(shib_state) = SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+                shib_state.touch();
+            }
+        }
+    }
+
+    /// Determines whether an authenticated session has outlived the session lifetime or timeout.
+    fn is_expired(&self, state: &State) -> bool {
+        let session = SessionData::<T>::borrow_from(state);
+
+        session.is_authenticated() && session.shib_state().map_or(false, |shib_state| {
+            shib_state.is_expired(self.config.session_lifetime, self.config.session_timeout)
+        })
+    }
+
+    /// Clears the session when the SP has reported that the SP session it's bound to was logged
+    /// out.
+    fn clear_if_logged_out(&self, state: &mut State) {
+        let index = match self.config.sp_session_index {
+            Some(ref index) => index,
+            None => return,
+        };
+
+        let logged_out = SessionData::<T>::borrow_from(state)
+            .shib_state()
+            .and_then(|shib_state| shib_state.sp_session_id())
+            .map_or(false, |sp_session_id| index.is_logged_out(sp_session_id));
+
+        if logged_out {
+            info!(
+                "[{}] SP session was logged out, clearing the session",
+                request_id(state)
+            );
+
+            *SessionData::<T>::borrow_mut_from(state) = T::default();
+        }
+    }
+
+    /// Compares an authenticated session with the SP session of the request, applying the
+    /// configured `SpSessionMismatch`. Returns false when the session must log in again.
+    fn check_sp_session(&self, state: &mut State) -> bool {
+        if self.config.sp_session_mismatch == SpSessionMismatch::Ignore {
+            return true;
+        }
+
+        let matches = {
+            let session = SessionData::<T>::borrow_from(state);
+            let headers = Headers::borrow_from(state);
+
+            match session.shib_state() {
+                Some(shib_state) if session.is_authenticated() => shib_state.matches_sp_session(
+                    header_value(headers, &self.config.sp_headers.session_id),
+                    header_value(headers, &self.config.sp_headers.identity_provider),
+                ),
+                _ => true,
+            }
+        };
+
+        if matches {
+            return true;
+        }
+
+        warn!(
+            "[{}] session doesn't match the SP session of the request, applying {:?}",
+            request_id(state),
+            self.config.sp_session_mismatch
+        );
+
+        match self.config.sp_session_mismatch {
+            SpSessionMismatch::Invalidate => {
+                *SessionData::<T>::borrow_mut_from(state) = T::default();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Determines whether the body of this request should be kept in the `PostDataStore` while the
+    /// user logs in, which is only possible for a browser submitting a form with `POST`, when the
+    /// session can record the pending login.
+    ///
+    /// The form is submitted again from the application's own origin, so a submission from another
+    /// site is never preserved, as that would let the site make the submission on the user's behalf
+    /// past any checks of `SameSite` cookies or the `Origin` header.
+    fn should_preserve_post(&self, state: &State, kind: RequestKind) -> bool {
+        let headers = Headers::borrow_from(state);
+
+        self.config.post_data_store.is_some() && kind == RequestKind::Browser
+            && *Method::borrow_from(state) == Method::Post
+            && is_preservable(header_value(headers, "Content-Type"))
+            && SessionData::<T>::borrow_from(state).shib_state().is_some()
+            && is_same_origin(headers)
+    }
+
+    /// Reads the body of the request into the `PostDataStore`, and then starts a login which will
+    /// submit it again once the login is received.
+    fn preserve_post_and_login(
+        self,
+        mut state: State,
+        kind: RequestKind,
+        mut options: LoginOptions,
+    ) -> Box<HandlerFuture> {
+        let store = match self.config.post_data_store {
+            Some(ref store) => store.clone(),
+            None => {
+                let response = self.login_required(&mut state, kind, options);
+                return Box::new(future::ok((state, response)));
+            }
+        };
+
+        let path = {
+            let uri = Uri::borrow_from(&state);
+
+            match uri.query() {
+                Some(query) => format!("{}?{}", uri.path(), query),
+                None => uri.path().to_owned(),
+            }
+        };
+
+        let max_body_size = store.max_body_size();
+        let body = Body::take_from(&mut state).fold(Vec::new(), move |mut body, chunk| {
+            if body.len() + chunk.len() > max_body_size {
+                return Err(hyper::Error::TooLarge);
+            }
+
+            body.extend_from_slice(&chunk);
+            Ok(body)
+        });
+
+        let f = body.then(move |body| {
+            match body {
+                Ok(body) => {
+                    options.post_data = store.insert(PreservedPost { path, body });
+
+                    if options.post_data.is_none() {
+                        warn!(
+                            "[{}] form submission not preserved, the store is full",
+                            request_id(&state)
+                        );
+                    }
+                }
+                Err(hyper::Error::TooLarge) => warn!(
+                    "[{}] form submission not preserved, the body is too large",
+                    request_id(&state)
+                ),
+                Err(e) => return Err((state, e.into_handler_error())),
+            }
+
+            let response = self.login_required(&mut state, kind, options);
+            Ok((state, response))
+        });
+
+        Box::new(f)
+    }
+
+    /// Decides whether to attempt a passive login for this request. An attempt is made at most
+    /// once per session, and only for a browser navigating with `GET`, so that every other
+    /// request is served anonymously.
+    fn begin_passive_attempt(&self, state: &mut State, kind: RequestKind) -> bool {
+        if kind != RequestKind::Browser || *Method::borrow_from(state) != Method::Get {
+            return false;
+        }
+
+        match SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+            Some(ref mut shib_state) if !shib_state.passive_attempted => {
+                shib_state.passive_attempted = true;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Builds a `Shibbleware` from configuration which is only known at runtime. Created by
+/// `Shibbleware::builder`.
+pub struct ShibblewareBuilder<T>
+where
+    T: AuthenticatedSession,
+{
+    config: ShibblewareConfig,
+    phantom: PhantomData<dyn SessionTypePhantom<T>>,
 }
 
-impl<T> Copy for Shibbleware<T> where T: AuthenticatedSession {}
+impl<T> ShibblewareBuilder<T>
+where
+    T: AuthenticatedSession,
+{
+    /// The location of the login route from `session_auth_router`. Defaults to `/auth/login`.
+    pub fn login_location<S>(mut self, login_location: S) -> ShibblewareBuilder<T>
+    where
+        S: Into<Arc<str>>,
+    {
+        self.config.login_location = login_location.into();
+        self
+    }
+
+    /// The name of the query string parameter which carries the return path to the login route,
+    /// when the session type doesn't provide `ShibSessionState`. Defaults to `return_path`, and
+    /// must match `AuthRouterConfig::with_return_parameter`.
+    pub fn return_parameter<S>(mut self, return_parameter: S) -> ShibblewareBuilder<T>
+    where
+        S: Into<Arc<str>>,
+    {
+        self.config.return_parameter = return_parameter.into();
+        self
+    }
+
+    /// The status code used to redirect browsers to the login route. Defaults to `303 See Other`,
+    /// which is always used for requests other than `GET` and `HEAD`.
+    pub fn redirect_status(mut self, redirect_status: RedirectStatus) -> ShibblewareBuilder<T> {
+        self.config.redirect_status = redirect_status;
+        self
+    }
+
+    /// Passes requests for paths which `path` matches through without authentication, either
+    /// `path` and any path beneath it or any path starting with `path`. See `PathMatch`.
+    pub fn exclude_path<S>(mut self, path: S, matching: PathMatch) -> ShibblewareBuilder<T>
+    where
+        S: Into<Arc<str>>,
+    {
+        self.config.excluded_paths.push((path.into(), matching));
+        self
+    }
+
+    /// Lets requests with `method` through without authentication, such as CORS preflights
+    /// (`OPTIONS`) or monitoring probes (`HEAD`), which browsers and load balancers send without
+    /// the session cookie. A later rule for the same method replaces the earlier one.
+    pub fn bypass_method(mut self, method: Method, bypass: MethodBypass) -> ShibblewareBuilder<T> {
+        self.config.bypass_methods.retain(|&(ref m, _)| *m != method);
+        self.config.bypass_methods.push((method, bypass));
+        self
+    }
+
+    /// Passes requests for which `predicate` returns `true` through without authentication.
+    pub fn bypass_when<F>(mut self, predicate: F) -> ShibblewareBuilder<T>
+    where
+        F: Fn(&State) -> bool + Send + Sync + RefUnwindSafe + 'static,
+    {
+        self.config.bypass_predicates.push(Arc::new(predicate));
+        self
+    }
+
+    /// Redirects to the SP's SessionInitiator, with the login route as the target, rather than
+    /// directly to the login route.
+    pub fn session_initiator(
+        mut self,
+        session_initiator: SessionInitiator,
+    ) -> ShibblewareBuilder<T> {
+        self.config.session_initiator = Some(session_initiator);
+        self
+    }
+
+    /// Attempts a passive login, once per session, rather than requiring authentication. If the
+    /// identity provider can't authenticate the user without interaction, the request is served
+    /// without authentication, so handlers must check `AuthenticatedSession::is_authenticated`.
+    ///
+    /// This requires the session type to provide `ShibSessionState`, and logins are always sent
+    /// through the SessionInitiator with `isPassive=true`.
+    ///
+    /// When a passive login fails, the SP returns the user to the login route without an SP
+    /// session, and the login route recognises the failure by the absence of the
+    /// `Shib-Session-ID` header named by `SpHeaders`. So the login route must be served with a lazy
+    /// session, rather than with `requireSession` as it can be without a SessionInitiator, or
+    /// mod_shib would start an interactive login instead. Configure a `session_initiator` too, so
+    /// that logins which aren't passive also start at the SessionInitiator. With Apache:
+    ///
+    /// ```text
+    /// <Location /auth/login>
+    ///   AuthType shibboleth
+    ///   ShibRequestSetting requireSession false
+    ///   Require shibboleth
+    /// </Location>
+    /// ```
+    pub fn passive(mut self, passive: bool) -> ShibblewareBuilder<T> {
+        self.config.passive = passive;
+        self
+    }
+
+    /// Requires the user to have authenticated with the context class `authn_context_class`, such
+    /// as `https://refeds.org/profile/mfa`. When called more than once, any of the classes is
+    /// sufficient.
+    ///
+    /// An authenticated user whose login doesn't satisfy the requirement is sent to log in again,
+    /// with the classes as the `authnContextClassRef` of the SessionInitiator. This requires the
+    /// session type to provide `ShibSessionState`.
+    pub fn require_authn_context(mut self, authn_context_class: &str) -> ShibblewareBuilder<T> {
+        self.config
+            .required_authn_contexts
+            .push(authn_context_class.to_owned());
+        self
+    }
+
+    /// Requires the identity provider to have authenticated the user within `max_age`, for
+    /// sensitive actions such as deleting a project. When the last authentication is older, the
+    /// user is sent to log in again with `forceAuthn=true`, and then returned to the original URL.
+    ///
+    /// The original URL of a request which isn't a `GET` can't be returned to, so the user is
+    /// returned to a page at the confirm location which submits the action again, without its
+    /// body, once they confirm it. Configure a `post_data_store` to submit a form with its body
+    /// instead. This requires the session type to provide `ShibSessionState`.
+    pub fn require_recent_authentication(mut self, max_age: Duration) -> ShibblewareBuilder<T> {
+        self.config.max_authentication_age = Some(max_age);
+        self
+    }
+
+    /// Requires the user to log in again once `lifetime` has passed since they logged in, whether
+    /// or not they've been active. This should match the `lifetime` of `<Sessions>` in
+    /// `shibboleth2.xml`.
+    ///
+    /// This requires the session type to provide `ShibSessionState`.
+    pub fn session_lifetime(mut self, lifetime: Duration) -> ShibblewareBuilder<T> {
+        self.config.session_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Requires the user to log in again once they've made no authenticated request for
+    /// `timeout`. This should match the `timeout` of `<Sessions>` in `shibboleth2.xml`.
+    ///
+    /// The time of the last request is only recorded once a minute, so that every request doesn't
+    /// write the session, and a session may expire up to a minute early.
+    ///
+    /// This requires the session type to provide `ShibSessionState`.
+    pub fn session_timeout(mut self, timeout: Duration) -> ShibblewareBuilder<T> {
+        self.config.session_timeout = Some(timeout);
+        self
+    }
+
+    /// The location of the confirm route from `session_auth_router`, which submits an action again
+    /// once a user who was interrupted by a login confirms it. Defaults to `/auth/confirm`.
+    pub fn confirm_location<S>(mut self, confirm_location: S) -> ShibblewareBuilder<T>
+    where
+        S: Into<Arc<str>>,
+    {
+        self.config.confirm_location = confirm_location.into();
+        self
+    }
+
+    /// Clears sessions bound to SP sessions which `index` reports as logged out. The same index
+    /// must be given to `AuthRouterConfig::with_sp_session_index`.
+    pub fn sp_session_index(mut self, index: SpSessionIndex) -> ShibblewareBuilder<T> {
+        self.config.sp_session_index = Some(index);
+        self
+    }
+
+    /// Compares each authenticated session with the `Shib-Session-ID` and `Shib-Identity-Provider`
+    /// of the request, as named by `sp_headers`, handling a session which doesn't match with
+    /// `mismatch`. A request without
+    /// an SP session doesn't match, so this is only suitable for routes which mod_shib also
+    /// protects, such as with lazy sessions, where it also ensures the headers can't be spoofed.
+    ///
+    /// This requires the session type to provide `ShibSessionState`.
+    pub fn sp_session_mismatch(mut self, mismatch: SpSessionMismatch) -> ShibblewareBuilder<T> {
+        self.config.sp_session_mismatch = mismatch;
+        self
+    }
+
+    /// Reads the SP session of each request from the headers named by `sp_headers`, which must
+    /// match `AuthRouterConfig::with_sp_headers`. See `SpHeaders`.
+    pub fn sp_headers(mut self, sp_headers: SpHeaders) -> ShibblewareBuilder<T> {
+        self.config.sp_headers = sp_headers;
+        self
+    }
+
+    /// Keeps the body of a form submitted by an unauthenticated browser in `store` while the user
+    /// logs in, and submits it again afterwards. The same store must be given to
+    /// `AuthRouterConfig::with_post_data_store`. See `PostDataStore`.
+    ///
+    /// This requires the session type to provide `ShibSessionState`.
+    pub fn post_data_store(mut self, store: PostDataStore) -> ShibblewareBuilder<T> {
+        self.config.post_data_store = Some(store);
+        self
+    }
+
+    /// Recognises API requests using `rules`, as in `Shibbleware::with_api_request_rules`.
+    pub fn api_request_rules(mut self, rules: ApiRequestRules) -> ShibblewareBuilder<T> {
+        self.config.api_request_rules = rules;
+        self
+    }
+
+    /// Creates the `Shibbleware`. A warning is logged for each setting which requires the session
+    /// type to provide `ShibSessionState` when it doesn't, because the setting has no effect.
+    pub fn build(self) -> Shibbleware<T> {
+        if T::default().shib_state().is_none() {
+            for setting in self.config.settings_requiring_state() {
+                warn!(
+                    "Shibbleware is configured with {}, which has no effect because the session \
+                     type doesn't provide ShibSessionState",
+                    setting
+                );
+            }
+        }
+
+        Shibbleware {
+            config: Arc::new(self.config),
+            phantom: PhantomData,
+        }
+    }
+}
 
 impl<T> Clone for Shibbleware<T>
 where
     T: AuthenticatedSession,
 {
     fn clone(&self) -> Self {
-        *self
+        Shibbleware {
+            config: self.config.clone(),
+            phantom: PhantomData,
+        }
     }
 }
 
@@ -60,7 +881,7 @@ where
     type Instance = Self;
 
     fn new_middleware(&self) -> io::Result<Self::Instance> {
-        Ok(*self)
+        Ok(self.clone())
     }
 }
 
@@ -68,36 +889,394 @@ define_encode_set! {
     pub QUERY_VALUE_ENCODE_SET = [QUERY_ENCODE_SET] | {'&', '=', ';'}
 }
 
+pub(crate) fn is_safe_method(method: &Method) -> bool {
+    *method == Method::Get || *method == Method::Head
+}
+
 impl<T> Middleware for Shibbleware<T>
 where
     T: AuthenticatedSession,
 {
-    fn call<Chain>(self, state: State, chain: Chain) -> Box<HandlerFuture>
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
     where
         Chain: FnOnce(State) -> Box<HandlerFuture>,
     {
-        if SessionData::<T>::borrow_from(&state).is_authenticated() {
-            chain(state)
-        } else {
-            let mut response = create_response(&state, StatusCode::SeeOther, None);
+        self.clear_if_logged_out(&mut state);
+        let live = self.check_sp_session(&mut state);
 
-            {
-                let uri = Uri::borrow_from(&state);
+        if let Some(bypass) = self.config.bypass(&state) {
+            // A bypassed request can't be sent to log in, so a session which would have to is
+            // cleared rather than reaching the handler as authenticated.
+            if !live || self.is_expired(&state) {
+                info!(
+                    "[{}] clearing a session which must log in again on a bypassed request",
+                    request_id(&state)
+                );
 
-                let return_path = match uri.query() {
-                    Some(query) => format!("{}?{}", uri.path(), query),
-                    None => uri.path().to_owned(),
-                };
+                *SessionData::<T>::borrow_mut_from(&mut state) = T::default();
+            }
 
-                let encoded_return_path = utf8_percent_encode(&return_path, QUERY_VALUE_ENCODE_SET);
+            return match bypass {
+                MethodBypass::Chain => chain(state),
+                MethodBypass::NoContent => {
+                    let response = create_response(&state, StatusCode::NoContent, None);
+                    Box::new(future::ok((state, response)))
+                }
+            };
+        }
+
+        let kind = self.config
+            .api_request_rules
+            .classify(Headers::borrow_from(&state));
+
+        let (authenticated, has_shib_state) = {
+            let session = SessionData::<T>::borrow_from(&state);
+            (session.is_authenticated(), session.shib_state().is_some())
+        };
+
+        if authenticated && !has_shib_state && self.config.has_session_requirements() {
+            error!(
+                "[{}] the session must meet requirements which are recorded in ShibSessionState, \
+                 but the session type doesn't provide it",
+                request_id(&state)
+            );
 
-                response.headers_mut().set(Location::new(format!(
-                    "{}?return_path={}",
-                    self.auth_login_location, encoded_return_path
-                )));
+            let response = create_response(&state, StatusCode::Forbidden, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        let options = if !authenticated && self.config.passive {
+            if !self.begin_passive_attempt(&mut state, kind) {
+                return chain(state);
             }
 
-            Box::new(future::ok((state, response)))
+            LoginOptions {
+                passive: true,
+                ..LoginOptions::default()
+            }
+        } else {
+            // An authenticated session which doesn't meet the requirements is stepped up by
+            // logging in again. The login is received into the existing session, which keeps its
+            // contents when it moves to a new identifier.
+            match self.required_login(&state, authenticated && live) {
+                Some(options) => options,
+                None => {
+                    if self.config.session_timeout.is_some() {
+                        self.touch_session(&mut state);
+                    }
+
+                    return chain(state);
+                }
+            }
+        };
+
+        if self.should_preserve_post(&state, kind) {
+            return self.preserve_post_and_login(state, kind, options);
         }
+
+        let response = self.login_required(&mut state, kind, options);
+        Box::new(future::ok((state, response)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::test::TestServer;
+
+    use router::AuthRouterConfig;
+    use test_support::*;
+
+    const OTHER_SP_SESSION: &[(&str, &str)] = &[
+        ("Shib-Session-ID", "_def"),
+        ("Shib-Identity-Provider", IDP),
+        ("uid", "jdoe"),
+    ];
+
+    fn test_server(shibbleware: Shibbleware<TestSession>) -> TestServer {
+        TestServer::new(router(shibbleware, AuthRouterConfig::new().insecure())).unwrap()
+    }
+
+    #[test]
+    fn test_settings_requiring_state() {
+        let builder = Shibbleware::<TestSession>::builder();
+        assert!(builder.config.settings_requiring_state().is_empty());
+
+        let builder = builder
+            .passive(true)
+            .session_timeout(Duration::from_secs(3600));
+        assert_eq!(
+            builder.config.settings_requiring_state(),
+            vec!["passive", "session_timeout"]
+        );
+    }
+
+    #[test]
+    fn test_sp_session_mismatch_invalidate() {
+        let server = test_server(
+            Shibbleware::builder()
+                .sp_session_mismatch(SpSessionMismatch::Invalidate)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+        assert_eq!(body(browser.get("/protected/page", SP_SESSION)), "jdoe");
+
+        // A request from another SP session clears the session, and must log in.
+        let response = browser.get("/protected/page", OTHER_SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert!(location(&response).starts_with("/auth/login?state="));
+        assert_eq!(body(browser.get("/", &[])), "anonymous");
+
+        // As must a request without an SP session.
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+        let response = browser.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(body(browser.get("/", &[])), "anonymous");
+    }
+
+    #[test]
+    fn test_sp_session_mismatch_login() {
+        let server = test_server(
+            Shibbleware::builder()
+                .sp_session_mismatch(SpSessionMismatch::Login)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+        assert_eq!(body(browser.get("/protected/page", SP_SESSION)), "jdoe");
+
+        // The authenticated session must log in again, and keeps its contents until it does.
+        let response = browser.get("/protected/page", OTHER_SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        let login = location(&response);
+        assert!(login.starts_with("/auth/login?state="));
+        assert_eq!(body(browser.get("/", &[])), "jdoe");
+
+        let response = browser.get(&login, OTHER_SP_SESSION);
+        assert_eq!(location(&response), "/auth/resume");
+        browser.get("/auth/resume", &[]);
+
+        // The session now belongs to the new SP session, and not the old one.
+        assert_eq!(body(browser.get("/protected/page", OTHER_SP_SESSION)), "jdoe");
+        let response = browser.get("/protected/page", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+    }
+
+    #[test]
+    fn test_post_data_round_trip() {
+        let store = PostDataStore::new();
+        let server = TestServer::new(router(
+            Shibbleware::<TestSession>::builder()
+                .post_data_store(store.clone())
+                .build(),
+            AuthRouterConfig::new()
+                .insecure()
+                .with_post_data_store(store),
+        )).unwrap();
+
+        // A submission from the application's own origin is submitted again after login.
+        let mut browser = Browser::new(&server);
+        let response = browser.post(
+            "/protected/page?draft=1",
+            "comment=Hello+world",
+            &[("Sec-Fetch-Site", "same-origin")],
+        );
+        assert_eq!(response.status(), StatusCode::SeeOther);
+
+        let response = browser.get(&location(&response), SP_SESSION);
+        assert_eq!(location(&response), "/auth/resume");
+
+        let response = browser.get("/auth/resume", &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        let html = body(response);
+        assert!(html.contains("<form method=\"post\" action=\"/protected/page?draft=1\">"));
+        assert!(html.contains("<input type=\"hidden\" name=\"comment\" value=\"Hello world\">"));
+        assert_eq!(body(browser.post("/protected/page", "comment=Hello+world", &[])), "jdoe");
+
+        // A submission from another site is not, so the user is asked to confirm it instead.
+        for headers in &[
+            &[("Sec-Fetch-Site", "cross-site")][..],
+            &[("Origin", "https://evil.example.com"), ("Host", "localhost")][..],
+        ] {
+            let mut browser = Browser::new(&server);
+            let response = browser.post("/protected/page", "comment=Hello+world", headers);
+            assert_eq!(response.status(), StatusCode::SeeOther);
+
+            let response = browser.get(&location(&response), SP_SESSION);
+            assert_eq!(location(&response), "/auth/resume");
+
+            let response = browser.get("/auth/resume", &[]);
+            assert_eq!(response.status(), StatusCode::SeeOther);
+            assert_eq!(location(&response), "/auth/confirm?action=/protected/page");
+        }
+    }
+
+    #[test]
+    fn test_confirm_after_login() {
+        let server = test_server(Shibbleware::new("/auth/login"));
+        let mut browser = Browser::new(&server);
+
+        // Without a `PostDataStore`, a form submission which starts a login returns to a page
+        // which asks the user to confirm it, rather than to a `GET` of the form's action.
+        let response = browser.post("/protected/page?draft=1", "comment=Hello+world", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+
+        let response = browser.get(&location(&response), SP_SESSION);
+        let response = browser.get(&location(&response), &[]);
+        assert_eq!(
+            location(&response),
+            "/auth/confirm?action=/protected/page?draft%3D1"
+        );
+
+        let response = browser.get(&location(&response), &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(
+            body(response).contains("<form method=\"post\" action=\"/protected/page?draft=1\">")
+        );
+
+        // Only paths on this site are submitted.
+        for action in &["https://evil.example.com/", "//evil.example.com/", "page"] {
+            let response = browser.get(&format!("/auth/confirm?action={}", action), &[]);
+            assert_eq!(response.status(), StatusCode::BadRequest);
+        }
+    }
+
+    #[test]
+    fn test_bypass_method() {
+        let server = test_server(
+            Shibbleware::builder()
+                .bypass_method(Method::Options, MethodBypass::Chain)
+                .bypass_method(Method::Options, MethodBypass::NoContent)
+                .bypass_method(Method::Post, MethodBypass::Chain)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+
+        // The later rule for a method replaces the earlier one.
+        let response = browser.request(Method::Options, "/protected/page", None, &[]);
+        assert_eq!(response.status(), StatusCode::NoContent);
+
+        let response = browser.post("/protected/page", "a=1", &[]);
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "anonymous");
+
+        let response = browser.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+    }
+
+    #[test]
+    fn test_exclude_path() {
+        let server = test_server(
+            Shibbleware::builder()
+                .exclude_path("/protected/page", PathMatch::Segments)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+        assert_eq!(body(browser.get("/protected/page", &[])), "anonymous");
+
+        let server = test_server(
+            Shibbleware::builder()
+                .exclude_path("/protected/pa", PathMatch::Segments)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+        let response = browser.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+
+        let server = test_server(
+            Shibbleware::builder()
+                .exclude_path("/protected/pa", PathMatch::Prefix)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+        assert_eq!(body(browser.get("/protected/page", &[])), "anonymous");
+
+        let config = ShibblewareConfig {
+            excluded_paths: vec![
+                (Arc::from("/static"), PathMatch::Segments),
+                (Arc::from("/assets/"), PathMatch::Segments),
+                (Arc::from("/favicon"), PathMatch::Prefix),
+            ],
+            ..Shibbleware::<TestSession>::builder().config
+        };
+
+        assert!(config.is_excluded("/static"));
+        assert!(config.is_excluded("/static/app.css"));
+        assert!(!config.is_excluded("/static-assets"));
+        assert!(config.is_excluded("/assets/app.js"));
+        assert!(config.is_excluded("/favicon.ico"));
+        assert!(config.is_excluded("/favicon-32x32.png"));
+        assert!(!config.is_excluded("/protected/page"));
+    }
+
+    #[test]
+    fn test_bypass_clears_invalid_session() {
+        fn bypassed(state: &State) -> bool {
+            Uri::borrow_from(state).query() == Some("bypass")
+        }
+
+        // A session whose SP session was logged out.
+        let index = SpSessionIndex::new();
+        let server = TestServer::new(router(
+            Shibbleware::<TestSession>::builder()
+                .sp_session_index(index.clone())
+                .bypass_when(bypassed)
+                .build(),
+            AuthRouterConfig::new()
+                .insecure()
+                .with_sp_session_index(index.clone()),
+        )).unwrap();
+        let mut browser = Browser::new(&server);
+
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+        assert_eq!(body(browser.get("/protected/page?bypass", SP_SESSION)), "jdoe");
+
+        index.logout("_abc");
+        assert_eq!(body(browser.get("/protected/page?bypass", SP_SESSION)), "anonymous");
+
+        // A session which must log in again with the SP session of the request.
+        let server = test_server(
+            Shibbleware::builder()
+                .sp_session_mismatch(SpSessionMismatch::Login)
+                .bypass_when(bypassed)
+                .build(),
+        );
+        let mut browser = Browser::new(&server);
+
+        log_in(&mut browser, "/protected/page", SP_SESSION);
+        let response = browser.get("/protected/page?bypass", OTHER_SP_SESSION);
+        assert_eq!(body(response), "anonymous");
+        assert_eq!(body(browser.get("/", &[])), "anonymous");
+    }
+
+    #[test]
+    fn test_sp_headers() {
+        let prefixed = &[
+            ("AJP_Shib-Session-ID", "_abc"),
+            ("AJP_Shib-Identity-Provider", IDP),
+            ("uid", "jdoe"),
+        ];
+
+        let server = TestServer::new(router(
+            Shibbleware::<TestSession>::builder()
+                .sp_session_mismatch(SpSessionMismatch::Invalidate)
+                .sp_headers(SpHeaders::with_prefix("AJP_"))
+                .build(),
+            AuthRouterConfig::new()
+                .insecure()
+                .with_sp_headers(SpHeaders::with_prefix("AJP_")),
+        )).unwrap();
+        let mut browser = Browser::new(&server);
+
+        log_in(&mut browser, "/protected/page", prefixed);
+        assert_eq!(body(browser.get("/protected/page", prefixed)), "jdoe");
+
+        // The unprefixed headers aren't the SP session's.
+        let response = browser.get("/protected/page", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
     }
 }
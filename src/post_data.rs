@@ -0,0 +1,281 @@
+//! Preservation of form submissions across the login redirect, so that a user whose session
+//! expired while they filled in a form doesn't lose their work.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use gotham::http::response::create_response;
+use gotham::state::State;
+use hyper::{Headers, Response, StatusCode};
+use mime;
+
+use headers::header_value;
+use metadata::escape_xml;
+use receiver::decode_query_component;
+use session_state::random_nonce;
+
+/// A form submission which was interrupted by a login.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, StateData)]
+pub(crate) struct PreservedPost {
+    /// The path and query string which the form was submitted to.
+    pub(crate) path: String,
+    /// The `application/x-www-form-urlencoded` body of the submission.
+    pub(crate) body: Vec<u8>,
+}
+
+/// Server-side storage for form submissions which were interrupted by a login, shared by
+/// `Shibbleware` and `session_auth_router`, like the SP's `postData` preservation.
+///
+/// When an unauthenticated browser submits an `application/x-www-form-urlencoded` form with
+/// `POST`, `Shibbleware` keeps the body here under a nonce which is recorded with the pending
+/// login. After the login is received, `Receiver::finish` serves a page which submits the form
+/// again, to its original path.
+///
+/// Only submissions which an HTML form can make again are preserved. Bodies of other types,
+/// including `multipart/form-data` file uploads, and requests with other methods, such as `PUT`,
+/// `PATCH` or `DELETE`, are answered with a login redirect and lost. Only submissions from the
+/// application's own origin are preserved, as shown by `Sec-Fetch-Site: same-origin` or by an
+/// `Origin` header which matches `Host`, so that another site can't have a submission made again
+/// from this one.
+///
+/// Submissions are held in memory, and are discarded once retrieved or once the retention period
+/// has passed. The size of each body and the number of submissions held are limited, so that
+/// unauthenticated requests can't exhaust memory.
+#[derive(Clone)]
+pub struct PostDataStore {
+    entries: Arc<Mutex<HashMap<String, (PreservedPost, Instant)>>>,
+    max_body_size: usize,
+    max_entries: usize,
+    retention: Duration,
+}
+
+impl PostDataStore {
+    /// Creates a store which holds up to 1024 bodies of up to 64 KiB each, for 10 minutes.
+    pub fn new() -> PostDataStore {
+        PostDataStore {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            max_body_size: 64 * 1024,
+            max_entries: 1024,
+            retention: Duration::from_secs(10 * 60),
+        }
+    }
+
+    /// Preserves only bodies of up to `max_body_size` bytes.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> PostDataStore {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Holds up to `max_entries` bodies at once.
+    pub fn with_max_entries(mut self, max_entries: usize) -> PostDataStore {
+        self.max_entries = max_entries;
+        self
+    }
+
+    /// Discards bodies which haven't been retrieved within `retention`.
+    pub fn with_retention(mut self, retention: Duration) -> PostDataStore {
+        self.retention = retention;
+        self
+    }
+
+    pub(crate) fn max_body_size(&self) -> usize {
+        self.max_body_size
+    }
+
+    /// Keeps `post`, returning the nonce under which it's kept, or `None` when the store is full.
+    pub(crate) fn insert(&self, post: PreservedPost) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let retention = self.retention;
+
+        entries.retain(|_, &mut (_, stored)| now.duration_since(stored) < retention);
+
+        if entries.len() >= self.max_entries {
+            return None;
+        }
+
+        let nonce = random_nonce();
+        entries.insert(nonce.clone(), (post, now));
+        Some(nonce)
+    }
+
+    /// Retrieves and discards the submission kept under `nonce`.
+    pub(crate) fn take(&self, nonce: &str) -> Option<PreservedPost> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        match entries.remove(nonce) {
+            Some((post, stored)) if stored.elapsed() < self.retention => Some(post),
+            _ => None,
+        }
+    }
+}
+
+impl Default for PostDataStore {
+    fn default() -> PostDataStore {
+        PostDataStore::new()
+    }
+}
+
+/// Determines whether a body with the content type `content_type` can be preserved and replayed
+/// by an HTML form.
+pub(crate) fn is_preservable(content_type: Option<&str>) -> bool {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .map_or(false, |essence| {
+            essence
+                .trim()
+                .eq_ignore_ascii_case("application/x-www-form-urlencoded")
+        })
+}
+
+/// Determines whether a request was made by the application's own origin. Browsers which send
+/// `Sec-Fetch-Site` are trusted to report it, and otherwise the host of `Origin` must match `Host`.
+/// A request with neither header is assumed to be from another site.
+pub(crate) fn is_same_origin(headers: &Headers) -> bool {
+    if let Some(site) = header_value(headers, "Sec-Fetch-Site") {
+        return site.eq_ignore_ascii_case("same-origin");
+    }
+
+    let host = match header_value(headers, "Host") {
+        Some(host) => host,
+        None => return false,
+    };
+
+    header_value(headers, "Origin")
+        .and_then(|origin| origin.splitn(2, "://").nth(1))
+        .map_or(false, |origin_host| origin_host.eq_ignore_ascii_case(host))
+}
+
+/// Responds with a page which submits the form preserved by `Shibbleware` again, if the login
+/// being finished interrupted a form submission. The blanket `Receiver` implementation does this
+/// in `Receiver::finish`, and other implementations can do the same.
+pub fn replay_preserved_post(state: &mut State) -> Option<Response> {
+    let post = state.try_take::<PreservedPost>()?;
+    let body = replay_form(&post);
+
+    Some(create_response(
+        state,
+        StatusCode::Ok,
+        Some((body.into_bytes(), mime::TEXT_HTML)),
+    ))
+}
+
+/// Renders a page which submits `post` to its original path as soon as it loads. The submit button
+/// is always shown, since the inline `onload` handler doesn't run where scripts are disabled or
+/// a Content Security Policy forbids inline handlers.
+fn replay_form(post: &PreservedPost) -> String {
+    let mut html = String::new();
+
+    html.push_str("<!DOCTYPE html>\n<html>\n<head><title>Continue</title></head>\n");
+    html.push_str("<body onload=\"document.forms[0].submit()\">\n");
+    html.push_str(&format!(
+        "<form method=\"post\" action=\"{}\">\n",
+        escape_xml(&post.path)
+    ));
+
+    let body = String::from_utf8_lossy(&post.body);
+    let pairs = body.split('&').filter(|pair| !pair.is_empty());
+
+    for pair in pairs {
+        let mut split = pair.splitn(2, '=');
+        let name = decode_query_component(split.next().unwrap_or(""));
+        let value = decode_query_component(split.next().unwrap_or(""));
+
+        html.push_str(&format!(
+            "<input type=\"hidden\" name=\"{}\" value=\"{}\">\n",
+            escape_xml(&name),
+            escape_xml(&value)
+        ));
+    }
+
+    html.push_str("<p>You have logged in. Continue to submit your form.</p>\n");
+    html.push_str("<button type=\"submit\">Continue</button>\n");
+    html.push_str("</form>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(body: &str) -> PreservedPost {
+        PreservedPost {
+            path: "/projects/42/comments?draft=1&x=\"".to_owned(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_is_preservable() {
+        assert!(is_preservable(Some("application/x-www-form-urlencoded")));
+        assert!(is_preservable(Some("Application/X-WWW-Form-Urlencoded; charset=UTF-8")));
+        assert!(!is_preservable(Some("multipart/form-data; boundary=abc")));
+        assert!(!is_preservable(Some("application/json")));
+        assert!(!is_preservable(None));
+    }
+
+    #[test]
+    fn test_is_same_origin() {
+        fn headers(pairs: &[(&str, &str)]) -> Headers {
+            let mut headers = Headers::new();
+            for &(name, value) in pairs {
+                headers.set_raw(name.to_owned(), value.to_owned());
+            }
+            headers
+        }
+
+        assert!(is_same_origin(&headers(&[("Sec-Fetch-Site", "same-origin")])));
+        assert!(!is_same_origin(&headers(&[("Sec-Fetch-Site", "same-site")])));
+        assert!(!is_same_origin(&headers(&[
+            ("Sec-Fetch-Site", "cross-site"),
+            ("Origin", "https://app.example.edu"),
+            ("Host", "app.example.edu"),
+        ])));
+
+        assert!(is_same_origin(&headers(&[
+            ("Origin", "https://app.example.edu:8443"),
+            ("Host", "app.example.edu:8443"),
+        ])));
+        assert!(!is_same_origin(&headers(&[
+            ("Origin", "https://evil.example.com"),
+            ("Host", "app.example.edu"),
+        ])));
+        assert!(!is_same_origin(&headers(&[("Origin", "null"), ("Host", "app.example.edu")])));
+        assert!(!is_same_origin(&headers(&[("Host", "app.example.edu")])));
+    }
+
+    #[test]
+    fn test_replay_form() {
+        let html = replay_form(&post("comment=Hello+%3Cworld%3E&tags=a&tags=b&empty="));
+
+        assert!(html.contains(
+            "<form method=\"post\" action=\"/projects/42/comments?draft=1&amp;x=&quot;\">"
+        ));
+        assert!(
+            html.contains("<input type=\"hidden\" name=\"comment\" value=\"Hello &lt;world&gt;\">")
+        );
+        assert_eq!(html.matches("name=\"tags\"").count(), 2);
+        assert!(html.contains("<input type=\"hidden\" name=\"empty\" value=\"\">"));
+
+        // The form can be submitted without scripts.
+        assert!(html.contains("<button type=\"submit\">Continue</button>"));
+        assert!(!html.contains("<noscript>"));
+    }
+
+    #[test]
+    fn test_store() {
+        let store = PostDataStore::new().with_max_entries(1);
+
+        let nonce = store.insert(post("a=1")).unwrap();
+        assert!(store.insert(post("b=2")).is_none());
+
+        assert_eq!(store.take(&nonce), Some(post("a=1")));
+        assert_eq!(store.take(&nonce), None);
+        assert!(store.insert(post("b=2")).is_some());
+
+        let store = PostDataStore::new().with_retention(Duration::from_secs(0));
+        let nonce = store.insert(post("a=1")).unwrap();
+        assert_eq!(store.take(&nonce), None);
+    }
+}
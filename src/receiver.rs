@@ -1,21 +1,40 @@
-use futures::future;
-use gotham::handler::{Handler, HandlerFuture, NewHandler};
+use chrono::Utc;
+use futures::{future, Future};
+use gotham::handler::{Handler, HandlerError, HandlerFuture, NewHandler};
 use gotham::http::response::create_response;
+use gotham::middleware::session::SessionData;
 use gotham::state::{request_id, FromState, State};
 use hyper::header::Location;
-use hyper::{Headers, Response, StatusCode};
+use hyper::{Headers, Response, StatusCode, Uri};
+use percent_encoding::percent_decode;
 use serde::Deserialize;
+use std::collections::BTreeSet;
 use std::io;
 use std::marker::PhantomData;
 use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+use std::time::Duration;
 
-use headers::deserialize;
+use attributes::{AttributeBag, AttributeFilter};
+use authenticated_session::AuthenticatedSession;
+use headers::{deserialize, deserialize_filtered, header_value};
+use middleware::SessionTypePhantom;
+use post_data::replay_preserved_post;
+use return_path::ReturnPathPolicy;
+use router::{AuthRouterConfig, UnsolicitedLogin};
+use session_handoff::{clear_handoff_cookie, discard_session, handoff_token, restore_session,
+                      Handoff, SessionHandoff};
+use session_state::{authn_context_satisfied, is_recent, parse_authentication_instant,
+                    PendingLogin};
 
 pub struct ReceiverFailed;
 
 pub trait Receiver<A>: Send + Sync {
     fn receive(&self, &mut State, A) -> Result<(), ReceiverFailed>;
 
+    /// Responds once a login has been received. With `session_auth_router`, the session is moved
+    /// to a new identifier between `receive` and `finish`, which are called on separate requests,
+    /// so anything which `receive` needs `finish` to see must be kept in the session.
     fn finish(&self, &mut State) -> Response;
 }
 
@@ -28,6 +47,10 @@ where
     }
 
     fn finish(&self, state: &mut State) -> Response {
+        if let Some(response) = replay_preserved_post(state) {
+            return response;
+        }
+
         let return_info = ReturnInfo::take_from(state);
 
         let mut response = create_response(state, StatusCode::SeeOther, None);
@@ -47,11 +70,47 @@ where
 {
 }
 
-#[derive(Deserialize, StateData, StaticResponseExtender)]
+#[derive(StateData)]
 pub(crate) struct ReturnInfo {
     return_path: Option<String>,
+    state: Option<String>,
+}
+
+impl ReturnInfo {
+    /// Reads the login parameters from the query string, where the return path is carried by the
+    /// parameter named `return_parameter`.
+    fn from_query(query: Option<&str>, return_parameter: &str) -> ReturnInfo {
+        ReturnInfo {
+            return_path: query_parameter(query, return_parameter),
+            state: query_parameter(query, "state"),
+        }
+    }
+}
+
+/// Reads the first value of the parameter `name` from a query string.
+pub(crate) fn query_parameter(query: Option<&str>, name: &str) -> Option<String> {
+    query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .map(|pair| pair.splitn(2, '='))
+        .filter_map(|mut split| match split.next() {
+            Some(n) if decode_query_component(n) == name => {
+                Some(decode_query_component(split.next().unwrap_or("")))
+            }
+            _ => None,
+        })
+        .next()
 }
 
+pub(crate) fn decode_query_component(component: &str) -> String {
+    let component = component.replace('+', " ");
+    percent_decode(component.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// Receives a login without using the session, as `auth_router` does. The user's attributes are
+/// passed to the receiver and the login is finished on the same request.
 pub struct LoginHandler<A, R>
 where
     R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
@@ -109,29 +168,791 @@ where
     A: for<'de> Deserialize<'de> + 'static,
 {
     fn handle(self, mut state: State) -> Box<HandlerFuture> {
-        let attrs = match deserialize::<A>(Headers::borrow_from(&state)) {
-            Ok(t) => t,
-            Err(e) => {
-                error!(
-                    "[{}] failed to deserialize user from incoming headers: {:?}",
-                    request_id(&state),
-                    e
+        let return_info = ReturnInfo::from_query(Uri::borrow_from(&state).query(), "return_path");
+        state.put(return_info);
+        validate_return_path(&ReturnPathPolicy::default(), &mut state);
+
+        let received = deserialize_attributes::<A>(None, &state)
+            .and_then(|attrs| self.r.receive(&mut state, attrs));
+
+        if let Err(ReceiverFailed) = received {
+            let response = create_response(&state, StatusCode::InternalServerError, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        let response = self.r.finish(&mut state);
+        Box::new(future::ok((state, response)))
+    }
+}
+
+/// How an incoming login relates to a login started by `Shibbleware` in the same session.
+enum LoginBinding {
+    /// The session type doesn't hold `ShibSessionState`, so the login can't be checked.
+    Unbound,
+    /// The login carried the nonce of the pending login.
+    Solicited(PendingLogin),
+    /// No pending login had the nonce.
+    Unsolicited,
+}
+
+/// Receives a login into the session, as `session_auth_router` does. The login is checked against
+/// the pending logins of the session, and the session is moved to a new identifier before the
+/// login is finished.
+pub(crate) struct SessionLoginHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    r: R,
+    config: Arc<AuthRouterConfig>,
+    handoff: SessionHandoff,
+    phantom: PhantomData<dyn AttributesTypePhantom<A>>,
+    session_phantom: PhantomData<dyn SessionTypePhantom<T>>,
+}
+
+impl<T, A, R> SessionLoginHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    pub(crate) fn new(r: R, handoff: SessionHandoff, config: Arc<AuthRouterConfig>) -> Self {
+        SessionLoginHandler {
+            r,
+            config,
+            handoff,
+            phantom: PhantomData,
+            session_phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, A, R> SessionLoginHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    /// Checks that this login was started by `Shibbleware` in the same session, by looking up the
+    /// nonce in the query string among the pending logins. Only the matching login is consumed.
+    fn bind_login(&self, state: &mut State) -> LoginBinding {
+        let nonce = state
+            .try_borrow_mut::<ReturnInfo>()
+            .and_then(|return_info| return_info.state.take());
+
+        let shib_state = match SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+            Some(shib_state) => shib_state,
+            None => return LoginBinding::Unbound,
+        };
+
+        match nonce.and_then(|nonce| shib_state.take_pending_login(&nonce)) {
+            Some(pending) => LoginBinding::Solicited(pending),
+            None => LoginBinding::Unsolicited,
+        }
+    }
+}
+
+impl<T, A, R> Clone for SessionLoginHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    fn clone(&self) -> Self {
+        SessionLoginHandler {
+            r: self.r,
+            config: self.config.clone(),
+            handoff: self.handoff.clone(),
+            phantom: PhantomData,
+            session_phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, A, R> NewHandler for SessionLoginHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> Result<Self, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl<T, A, R> Handler for SessionLoginHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    fn handle(self, mut state: State) -> Box<HandlerFuture> {
+        if !has_session::<T>(&state) {
+            let response = create_response(&state, StatusCode::InternalServerError, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        let return_info = ReturnInfo::from_query(
+            Uri::borrow_from(&state).query(),
+            &self.config.return_parameter,
+        );
+        state.put(return_info);
+
+        match self.bind_login(&mut state) {
+            LoginBinding::Unbound => {}
+            LoginBinding::Solicited(pending) => {
+                ReturnInfo::borrow_mut_from(&mut state).return_path = Some(pending.return_path);
+
+                // When the identity provider can't authenticate the user passively, the SP returns
+                // them to the login route without a session. The user continues anonymously. This
+                // relies on mod_shib serving the login route with a lazy session, as described by
+                // `ShibblewareBuilder::passive`.
+                let sp_session = header_value(
+                    Headers::borrow_from(&state),
+                    &self.config.sp_headers.session_id,
+                ).map_or(false, |sp_session_id| !sp_session_id.is_empty());
+
+                if pending.passive && !sp_session {
+                    info!(
+                        "[{}] passive login failed, continuing without authentication",
+                        request_id(&state)
+                    );
+
+                    validate_return_path(&self.config.return_paths, &mut state);
+                    let return_path = ReturnInfo::take_from(&mut state).return_path;
+
+                    let mut response = create_response(&state, StatusCode::SeeOther, None);
+                    response
+                        .headers_mut()
+                        .set(Location::new(return_path.unwrap_or_else(|| "/".to_owned())));
+                    return Box::new(future::ok((state, response)));
+                }
+
+                // The identity provider may satisfy a step-up login with a weaker context than the
+                // one requested, so the context it reports is checked before accepting the login.
+                let satisfied = authn_context_satisfied(
+                    &pending.required_authn_contexts,
+                    header_value(
+                        Headers::borrow_from(&state),
+                        &self.config.sp_headers.authn_context_class,
+                    ),
                 );
 
-                let response = create_response(&state, StatusCode::InternalServerError, None);
-                return Box::new(future::ok((state, response)));
+                if !satisfied {
+                    warn!(
+                        "[{}] rejected login which didn't satisfy the required authentication \
+                         context, one of {:?}",
+                        request_id(&state),
+                        pending.required_authn_contexts
+                    );
+
+                    let response = create_response(&state, StatusCode::Forbidden, None);
+                    return Box::new(future::ok((state, response)));
+                }
+
+                // Likewise, the identity provider may not honour `forceAuthn`.
+                if let Some(max_age) = pending.max_authentication_age {
+                    let recent = header_value(
+                        Headers::borrow_from(&state),
+                        &self.config.sp_headers.authentication_instant,
+                    ).and_then(parse_authentication_instant)
+                        .map_or(false, |instant| is_recent(instant, Duration::from_secs(max_age)));
+
+                    if !recent {
+                        warn!(
+                            "[{}] rejected login which wasn't authenticated within {} seconds",
+                            request_id(&state),
+                            max_age
+                        );
+
+                        let response = create_response(&state, StatusCode::Forbidden, None);
+                        return Box::new(future::ok((state, response)));
+                    }
+                }
+
+                // The login interrupted a form submission, which `finish` submits again.
+                let preserved = match (pending.post_data, self.config.post_data_store.as_ref()) {
+                    (Some(ref nonce), Some(store)) => store.take(nonce),
+                    _ => None,
+                };
+
+                if let Some(preserved) = preserved {
+                    state.put(preserved);
+                }
             }
+            LoginBinding::Unsolicited => match self.config.unsolicited_login {
+                UnsolicitedLogin::Accept => {}
+                UnsolicitedLogin::RedirectTo(ref path) => {
+                    warn!(
+                        "[{}] rejected login which wasn't started in this session",
+                        request_id(&state)
+                    );
+
+                    let mut response = create_response(&state, StatusCode::SeeOther, None);
+                    response.headers_mut().set(Location::new(path.clone()));
+                    return Box::new(future::ok((state, response)));
+                }
+            },
+        }
+
+        validate_return_path(&self.config.return_paths, &mut state);
+
+        if let Err(ReceiverFailed) = receive_login::<T, A, R>(&self.r, &self.config, &mut state) {
+            let response = create_response(&state, StatusCode::InternalServerError, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        // The session which received the login may have been planted by someone else, so it's
+        // moved to a new identifier by `ResumeHandler` before the login is finished.
+        let return_path = ReturnInfo::take_from(&mut state).return_path;
+
+        let discarded = discard_session::<T>(
+            &self.handoff,
+            self.config.secure_cookies,
+            &mut state,
+            return_path,
+        );
+
+        let response = match discarded {
+            Ok(set_cookie) => {
+                let location = resume_location(Uri::borrow_from(&state).path());
+
+                let mut response = create_response(&state, StatusCode::SeeOther, None);
+                response.headers_mut().set(Location::new(location));
+                response.headers_mut().append_raw("Set-Cookie", set_cookie);
+                response
+            }
+            Err(ReceiverFailed) => create_response(&state, StatusCode::InternalServerError, None),
         };
 
-        match self.r.receive(&mut state, attrs) {
-            Ok(()) => {}
-            Err(ReceiverFailed) => {
-                let response = create_response(&state, StatusCode::InternalServerError, None);
-                return Box::new(future::ok((state, response)));
+        Box::new(future::ok((state, response)))
+    }
+}
+
+/// Replaces a missing or disallowed return path with the default of `policy`, so that
+/// `Receiver::finish` can't be made to redirect to another site.
+fn validate_return_path(policy: &ReturnPathPolicy, state: &mut State) {
+    let id = request_id(state).to_owned();
+
+    if let Some(return_info) = state.try_borrow_mut::<ReturnInfo>() {
+        let allowed = match return_info.return_path {
+            Some(ref path) if policy.is_allowed(path) => true,
+            Some(ref path) => {
+                warn!(
+                    "[{}] rejected return path {:?}, using {:?} instead",
+                    id,
+                    path,
+                    policy.default_path()
+                );
+                false
             }
+            None => false,
+        };
+
+        if !allowed {
+            return_info.return_path = Some(policy.default_path().to_owned());
         }
+    }
+}
 
-        let response = self.r.finish(&mut state);
-        Box::new(future::ok((state, response)))
+/// Determines whether the session middleware for the session type `T` ran before this request,
+/// logging the misconfiguration when it didn't.
+pub(crate) fn has_session<T>(state: &State) -> bool
+where
+    T: AuthenticatedSession,
+{
+    let has_session = state.has::<SessionData<T>>();
+
+    if !has_session {
+        error!(
+            "[{}] no session, session_auth_router must be behind the session middleware for the \
+             session type given to it",
+            request_id(state)
+        );
+    }
+
+    has_session
+}
+
+/// The location of the resume route, which is beside the login route at `login_path` wherever
+/// `session_auth_router` is mounted.
+fn resume_location(login_path: &str) -> String {
+    match login_path.rfind('/') {
+        Some(i) => format!("{}/resume", &login_path[..i]),
+        None => "/resume".to_owned(),
+    }
+}
+
+/// Completes a login by moving the session which `SessionLoginHandler` discarded into the new
+/// session of this request, and then calling `Receiver::finish`.
+pub(crate) struct ResumeHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    r: R,
+    config: Arc<AuthRouterConfig>,
+    handoff: SessionHandoff,
+    phantom: PhantomData<dyn AttributesTypePhantom<A>>,
+    session_phantom: PhantomData<dyn SessionTypePhantom<T>>,
+}
+
+impl<T, A, R> ResumeHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    pub(crate) fn new(r: R, handoff: SessionHandoff, config: Arc<AuthRouterConfig>) -> Self {
+        ResumeHandler {
+            r,
+            config,
+            handoff,
+            phantom: PhantomData,
+            session_phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, A, R> Clone for ResumeHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    fn clone(&self) -> Self {
+        ResumeHandler {
+            r: self.r,
+            config: self.config.clone(),
+            handoff: self.handoff.clone(),
+            phantom: PhantomData,
+            session_phantom: PhantomData,
+        }
+    }
+}
+
+impl<T, A, R> NewHandler for ResumeHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    type Instance = Self;
+
+    fn new_handler(&self) -> Result<Self, io::Error> {
+        Ok(self.clone())
+    }
+}
+
+impl<T, A, R> Handler for ResumeHandler<T, A, R>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A> + Send + Sync + Copy + RefUnwindSafe,
+    A: for<'de> Deserialize<'de> + 'static,
+{
+    fn handle(self, mut state: State) -> Box<HandlerFuture> {
+        if !has_session::<T>(&state) {
+            let response = create_response(&state, StatusCode::InternalServerError, None);
+            return Box::new(future::ok((state, response)));
+        }
+
+        let taken: Box<dyn Future<Item = Option<Handoff>, Error = ()> + Send> =
+            match handoff_token(Headers::borrow_from(&state)) {
+                Some(token) => self.handoff.take(request_id(&state), token),
+                None => Box::new(future::ok(None)),
+            };
+
+        let f = taken.then(move |taken| {
+            let mut response = match taken {
+                Ok(Some(handoff)) => match restore_session::<T>(&mut state, &handoff) {
+                    Ok(()) => {
+                        state.put(ReturnInfo {
+                            return_path: handoff.return_path,
+                            state: None,
+                        });
+
+                        if let Some(preserved_post) = handoff.preserved_post {
+                            state.put(preserved_post);
+                        }
+
+                        self.r.finish(&mut state)
+                    }
+                    Err(ReceiverFailed) => {
+                        create_response(&state, StatusCode::InternalServerError, None)
+                    }
+                },
+                Ok(None) => {
+                    warn!(
+                        "[{}] no login to resume, it may have expired or already been resumed",
+                        request_id(&state)
+                    );
+
+                    let mut response = create_response(&state, StatusCode::SeeOther, None);
+                    response.headers_mut().set(Location::new(
+                        self.config.return_paths.default_path().to_owned(),
+                    ));
+                    response
+                }
+                Err(()) => create_response(&state, StatusCode::InternalServerError, None),
+            };
+
+            response
+                .headers_mut()
+                .append_raw("Set-Cookie", clear_handoff_cookie());
+            Ok::<_, (State, HandlerError)>((state, response))
+        });
+
+        Box::new(f)
+    }
+}
+
+/// Deserializes the user's attributes from the request and passes them to the receiver, recording
+/// the login in the session when it's accepted.
+pub(crate) fn receive_login<T, A, R>(
+    r: &R,
+    config: &AuthRouterConfig,
+    state: &mut State,
+) -> Result<(), ReceiverFailed>
+where
+    T: AuthenticatedSession,
+    R: Receiver<A>,
+    A: for<'de> Deserialize<'de>,
+{
+    let attrs = deserialize_attributes::<A>(config.attribute_filter.as_ref(), state)?;
+    let roles = map_roles(config, state)?;
+
+    if let Err(ReceiverFailed) = r.receive(state, attrs) {
+        // Roles granted by an earlier login in this session mustn't outlive a rejected login.
+        if let Some(shib_state) = SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+            shib_state.roles.clear();
+        }
+
+        return Err(ReceiverFailed);
+    }
+
+    if let Some(roles) = roles {
+        grant_roles::<T>(state, roles);
+    }
+
+    record_login::<T>(config, state);
+    Ok(())
+}
+
+/// Deserializes the user's attributes from the request headers, or from only those identified by
+/// `filter`.
+fn deserialize_attributes<A>(
+    filter: Option<&AttributeFilter>,
+    state: &State,
+) -> Result<A, ReceiverFailed>
+where
+    A: for<'de> Deserialize<'de>,
+{
+    let attrs = match filter {
+        Some(filter) => deserialize_filtered::<A>(Headers::borrow_from(state), filter),
+        None => deserialize::<A>(Headers::borrow_from(state)),
+    };
+
+    attrs.map_err(|e| {
+        error!(
+            "[{}] failed to deserialize user from incoming headers: {:?}",
+            request_id(state),
+            e
+        );
+        ReceiverFailed
+    })
+}
+
+/// Records details of the login which the session needs to enforce later requirements, after
+/// the receiver has accepted it.
+fn record_login<T>(config: &AuthRouterConfig, state: &mut State)
+where
+    T: AuthenticatedSession,
+{
+    let (authn_context_class, authentication_instant, sp_session_id, identity_provider) = {
+        let headers = Headers::borrow_from(state);
+        let names = &config.sp_headers;
+        (
+            header_value(headers, &names.authn_context_class).map(String::from),
+            header_value(headers, &names.authentication_instant)
+                .and_then(parse_authentication_instant),
+            header_value(headers, &names.session_id).map(String::from),
+            header_value(headers, &names.identity_provider).map(String::from),
+        )
+    };
+
+    if let (Some(index), Some(sp_session_id)) =
+        (config.sp_session_index.as_ref(), sp_session_id.as_ref())
+    {
+        index.register(sp_session_id);
+    }
+
+    if let Some(shib_state) = SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+        shib_state.authn_context_class = authn_context_class;
+        shib_state.authentication_instant = authentication_instant;
+        shib_state.sp_session_id = sp_session_id;
+        shib_state.identity_provider = identity_provider;
+        shib_state.authenticated_at = Some(Utc::now().timestamp());
+        shib_state.touch();
+    }
+}
+
+/// Maps the user's attributes to roles with the configured `RoleMapping`, if there is one. This
+/// happens before the receiver runs, so that a login whose attributes can't be read is rejected
+/// without receiving it.
+fn map_roles(
+    config: &AuthRouterConfig,
+    state: &State,
+) -> Result<Option<BTreeSet<String>>, ReceiverFailed> {
+    let role_mapping = match config.role_mapping {
+        Some(ref role_mapping) => role_mapping,
+        None => return Ok(None),
+    };
+
+    let bag = match config.attribute_filter {
+        Some(ref filter) => {
+            deserialize_filtered::<AttributeBag>(Headers::borrow_from(state), filter)
+        }
+        None => deserialize::<AttributeBag>(Headers::borrow_from(state)),
+    };
+
+    match bag {
+        Ok(bag) => Ok(Some(role_mapping.roles(&bag))),
+        Err(e) => {
+            error!(
+                "[{}] failed to read attributes for role mapping: {:?}",
+                request_id(state),
+                e
+            );
+            Err(ReceiverFailed)
+        }
+    }
+}
+
+/// Grants `roles` to the user once the receiver has accepted the login, so they're available to
+/// later requests.
+fn grant_roles<T>(state: &mut State, roles: BTreeSet<String>)
+where
+    T: AuthenticatedSession,
+{
+    let id = request_id(state).to_owned();
+
+    match SessionData::<T>::borrow_mut_from(state).shib_state_mut() {
+        Some(shib_state) => shib_state.roles = roles,
+        None => warn!(
+            "[{}] roles aren't kept because the session type doesn't provide ShibSessionState",
+            id
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::middleware::session::MemoryBackend;
+    use gotham::router::builder::*;
+    use gotham::test::TestServer;
+
+    use middleware::Shibbleware;
+    use router::{auth_router, session_auth_router};
+    use test_support::*;
+
+    fn test_server() -> TestServer {
+        TestServer::new(router(
+            Shibbleware::<TestSession>::new("/auth/login"),
+            AuthRouterConfig::new().insecure(),
+        )).unwrap()
+    }
+
+    #[test]
+    fn test_login_rotates_session() {
+        let server = test_server();
+        let mut browser = Browser::new(&server);
+
+        let response = browser.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert!(location(&response).starts_with("/auth/login?state="));
+        let planted = browser.cookie(SESSION_COOKIE).unwrap().to_owned();
+
+        // The session which received the login is discarded, and its cookie expired.
+        let response = browser.get(&location(&response), SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/auth/resume");
+        assert_eq!(browser.cookie(SESSION_COOKIE), None);
+
+        let response = browser.get("/auth/resume", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/protected/page");
+        assert_eq!(browser.cookie("_shib_gotham_login"), None);
+
+        let rotated = browser.cookie(SESSION_COOKIE).unwrap().to_owned();
+        assert_ne!(rotated, planted);
+        assert_eq!(body(browser.get("/protected/page", &[])), "jdoe");
+
+        // Whoever planted the session identifier before login isn't authenticated with it.
+        let mut planter = Browser::new(&server);
+        planter.set_cookie(SESSION_COOKIE, &planted);
+
+        let response = planter.get("/protected/page", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert!(location(&response).starts_with("/auth/login?state="));
+    }
+
+    #[test]
+    fn test_resume_once() {
+        let server = test_server();
+        let mut browser = Browser::new(&server);
+
+        let response = browser.get("/protected/page", &[]);
+        browser.get(&location(&response), SP_SESSION);
+        let token = browser.cookie("_shib_gotham_login").unwrap().to_owned();
+        browser.get("/auth/resume", &[]);
+
+        // A handoff token can only be used once.
+        let mut replayer = Browser::new(&server);
+        replayer.set_cookie("_shib_gotham_login", &token);
+
+        let response = replayer.get("/auth/resume", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/");
+        assert_eq!(body(replayer.get("/", &[])), "anonymous");
+    }
+
+    #[test]
+    fn test_resume_on_another_instance() {
+        let backend = MemoryBackend::default();
+        let first = TestServer::new(router_with_backend(
+            Shibbleware::<TestSession>::new("/auth/login"),
+            AuthRouterConfig::new().insecure(),
+            backend.clone(),
+        )).unwrap();
+        let second = TestServer::new(router_with_backend(
+            Shibbleware::<TestSession>::new("/auth/login"),
+            AuthRouterConfig::new().insecure(),
+            backend,
+        )).unwrap();
+
+        let mut browser = Browser::new(&first);
+        let response = browser.get("/protected/page", &[]);
+        browser.get(&location(&response), SP_SESSION);
+        let token = browser.cookie("_shib_gotham_login").unwrap().to_owned();
+
+        // The login is resumed by an instance which shares the session backend.
+        let mut browser = Browser::new(&second);
+        browser.set_cookie("_shib_gotham_login", &token);
+
+        let response = browser.get("/auth/resume", &[]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/protected/page");
+        assert_eq!(body(browser.get("/protected/page", &[])), "jdoe");
+    }
+
+    #[test]
+    fn test_parallel_logins() {
+        let server = test_server();
+        let mut browser = Browser::new(&server);
+
+        // Each tab starts its own login, and the first to be completed is still accepted.
+        let first = location(&browser.get("/protected/page?tab=1", &[]));
+        let second = location(&browser.get("/protected/page?tab=2", &[]));
+        assert_ne!(first, second);
+
+        let response = browser.get(&first, SP_SESSION);
+        assert_eq!(location(&response), "/auth/resume");
+
+        let response = browser.get("/auth/resume", &[]);
+        assert_eq!(location(&response), "/protected/page?tab=1");
+        assert_eq!(body(browser.get("/protected/page", &[])), "jdoe");
+    }
+
+    #[test]
+    fn test_login_without_session() {
+        let server = TestServer::new(build_simple_router(|route| {
+            route
+                .delegate("/auth")
+                .to_router(session_auth_router::<TestSession, TestAttributes, _, _>(
+                    receive_user,
+                    MemoryBackend::default(),
+                ));
+        })).unwrap();
+        let mut browser = Browser::new(&server);
+
+        let response = browser.get("/auth/login?state=abc123", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+
+        let response = browser.get("/auth/resume", &[]);
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+    }
+
+    fn receive_anyone(
+        _state: &mut State,
+        _attributes: TestAttributes,
+    ) -> Result<(), ReceiverFailed> {
+        Ok(())
+    }
+
+    #[test]
+    fn test_login_without_session_type() {
+        let server = TestServer::new(build_simple_router(|route| {
+            route.delegate("/auth").to_router(auth_router(receive_anyone));
+        })).unwrap();
+        let mut browser = Browser::new(&server);
+
+        // The login is finished on the same request, without a session.
+        let response = browser.get("/auth/login?return_path=%2Fprotected%2Fpage", SP_SESSION);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(location(&response), "/protected/page");
+
+        let response = browser.get(
+            "/auth/login?return_path=https%3A%2F%2Fevil.example%2F",
+            SP_SESSION,
+        );
+        assert_eq!(location(&response), "/");
+
+        let response = browser.get("/auth/login", &[("Shib-Session-ID", "_abc")]);
+        assert_eq!(response.status(), StatusCode::InternalServerError);
+    }
+
+    #[test]
+    fn test_resume_location() {
+        assert_eq!(resume_location("/auth/login"), "/auth/resume");
+        assert_eq!(resume_location("/login"), "/resume");
+    }
+
+    #[test]
+    fn test_return_info_from_query() {
+        let return_info = ReturnInfo::from_query(
+            Some("return_path=%2Fprotected%3Fpage%3D2%26sort%3Dname&state=abc123"),
+            "return_path",
+        );
+        assert_eq!(
+            return_info.return_path,
+            Some("/protected?page=2&sort=name".to_owned())
+        );
+        assert_eq!(return_info.state, Some("abc123".to_owned()));
+
+        let return_info =
+            ReturnInfo::from_query(Some("target=%2Fprotected&return_path=%2F"), "target");
+        assert_eq!(return_info.return_path, Some("/protected".to_owned()));
+        assert_eq!(return_info.state, None);
+
+        let return_info = ReturnInfo::from_query(None, "return_path");
+        assert_eq!(return_info.return_path, None);
+    }
+
+    #[test]
+    fn test_query_parameter() {
+        let query = Some("a=1&action=%2Fprojects%2F42%2Fdelete%3Fconfirm%3Dtrue&action=%2F");
+
+        assert_eq!(
+            query_parameter(query, "action"),
+            Some("/projects/42/delete?confirm=true".to_owned())
+        );
+        assert_eq!(query_parameter(query, "missing"), None);
+        assert_eq!(query_parameter(None, "action"), None);
     }
 }
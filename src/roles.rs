@@ -0,0 +1,183 @@
+//! Mapping of attributes to application roles, from rules which are loaded at runtime so that
+//! access can be changed without rebuilding the application.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::{error, fmt};
+
+use serde_json;
+use toml;
+
+use attributes::AttributeBag;
+
+/// An error which occurred while loading role mapping rules.
+#[derive(Debug)]
+pub struct RoleMappingError {
+    msg: String,
+}
+
+impl error::Error for RoleMappingError {
+    fn description(&self) -> &str {
+        "unable to load role mapping rules"
+    }
+}
+
+impl fmt::Display for RoleMappingError {
+    fn fmt(&self, out: &mut fmt::Formatter) -> fmt::Result {
+        write!(out, "unable to load role mapping rules: {}", self.msg)
+    }
+}
+
+/// A rule which grants `role` to users whose `attribute` has `value` among its values.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RoleRule {
+    pub role: String,
+    pub attribute: String,
+    pub value: String,
+}
+
+/// Rules which map attributes to application roles, evaluated by the login route of
+/// `session_auth_router` when a user logs in. The resulting roles are available from
+/// `ShibSessionState::roles`.
+///
+/// In TOML, the rules are written as:
+///
+/// ```toml
+/// [[rules]]
+/// role = "Admin"
+/// attribute = "eduPersonEntitlement"
+/// value = "urn:mace:aaf.edu.au:x:admin"
+///
+/// [[rules]]
+/// role = "Staff"
+/// attribute = "eduPersonScopedAffiliation"
+/// value = "staff@uni.edu.au"
+/// ```
+///
+/// and in JSON as `{"rules": [{"role": "Admin", "attribute": ..., "value": ...}]}`. Attribute names
+/// are compared ignoring case, and values are compared exactly.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoleMapping {
+    rules: Vec<RoleRule>,
+}
+
+impl RoleMapping {
+    pub fn new(rules: Vec<RoleRule>) -> RoleMapping {
+        RoleMapping { rules }
+    }
+
+    /// Parses rules in TOML.
+    pub fn from_toml(source: &str) -> Result<RoleMapping, RoleMappingError> {
+        toml::from_str(source).map_err(|e| RoleMappingError {
+            msg: format!("{}", e),
+        })
+    }
+
+    /// Parses rules in JSON.
+    pub fn from_json(source: &str) -> Result<RoleMapping, RoleMappingError> {
+        serde_json::from_str(source).map_err(|e| RoleMappingError {
+            msg: format!("{}", e),
+        })
+    }
+
+    /// Loads rules from the file at `path`, which is parsed as JSON if its extension is `.json`,
+    /// and as TOML otherwise.
+    pub fn from_file<P>(path: P) -> Result<RoleMapping, RoleMappingError>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        let mut source = String::new();
+
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(|e| RoleMappingError {
+                msg: format!("{}: {}", path.display(), e),
+            })?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => RoleMapping::from_json(&source),
+            _ => RoleMapping::from_toml(&source),
+        }
+    }
+
+    /// The rules, in the order they were given.
+    pub fn rules(&self) -> &[RoleRule] {
+        &self.rules
+    }
+
+    /// Evaluates the rules against `attributes`, returning the roles granted.
+    pub fn roles(&self, attributes: &AttributeBag) -> BTreeSet<String> {
+        self.rules
+            .iter()
+            .filter(|rule| {
+                attributes
+                    .get(&rule.attribute)
+                    .map_or(false, |values| values.contains(&rule.value))
+            })
+            .map(|rule| rule.role.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Headers;
+
+    use headers::deserialize;
+
+    #[test]
+    fn test_toml_and_json_agree() {
+        let from_toml = RoleMapping::from_toml(
+            r#"
+                [[rules]]
+                role = "Admin"
+                attribute = "eduPersonEntitlement"
+                value = "urn:mace:aaf.edu.au:x:admin"
+            "#,
+        ).unwrap();
+
+        let from_json = RoleMapping::from_json(
+            r#"{"rules": [{
+                "role": "Admin",
+                "attribute": "eduPersonEntitlement",
+                "value": "urn:mace:aaf.edu.au:x:admin"
+            }]}"#,
+        ).unwrap();
+
+        assert_eq!(from_toml.rules(), from_json.rules());
+        assert!(RoleMapping::from_json(r#"{"rules": [{"role": "Admin"}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_roles() {
+        let mapping = RoleMapping::new(vec![
+            RoleRule {
+                role: "Admin".to_owned(),
+                attribute: "eduPersonEntitlement".to_owned(),
+                value: "urn:mace:aaf.edu.au:x:admin".to_owned(),
+            },
+            RoleRule {
+                role: "Staff".to_owned(),
+                attribute: "eduPersonScopedAffiliation".to_owned(),
+                value: "staff@uni.edu.au".to_owned(),
+            },
+        ]);
+
+        let mut headers = Headers::new();
+        headers.set_raw(
+            "eduPersonScopedAffiliation",
+            "member@uni.edu.au;staff@uni.edu.au",
+        );
+        headers.set_raw("eduPersonEntitlement", "urn:mace:aaf.edu.au:x:user");
+
+        let roles = mapping.roles(&deserialize::<AttributeBag>(&headers).unwrap());
+
+        assert_eq!(roles.into_iter().collect::<Vec<_>>(), vec!["Staff"]);
+    }
+}
@@ -1,24 +1,324 @@
 use std::fmt::Debug;
 use std::panic::RefUnwindSafe;
+use std::sync::Arc;
 
 use serde::Deserialize;
 
+use gotham::middleware::session::NewBackend;
 use gotham::router::Router;
 use gotham::router::builder::*;
 
-use receiver::{LoginHandler, Receiver, ReturnInfo};
+use attributes::AttributeFilter;
+use authenticated_session::AuthenticatedSession;
+use confirm::ConfirmHandler;
+use logout::{FrontChannelLogoutHandler, LogoutHandler, LogoutReceiver};
+use logout_notification::{LogoutNotificationHandler, SpSessionIndex};
+use post_data::PostDataStore;
+use receiver::{LoginHandler, Receiver, ResumeHandler, SessionLoginHandler};
+use return_path::ReturnPathPolicy;
+use roles::RoleMapping;
+use session_handoff::SessionHandoff;
+use sp_headers::SpHeaders;
+
+/// What to do with a login which wasn't started by `Shibbleware` in the same session. Logins are
+/// only checked when the session type provides `ShibSessionState`.
+#[derive(Clone, Debug)]
+pub enum UnsolicitedLogin {
+    /// Redirect to the given path without receiving the user's attributes.
+    RedirectTo(String),
+    /// Receive the user's attributes anyway, as when logins aren't checked.
+    Accept,
+}
+
+impl Default for UnsolicitedLogin {
+    fn default() -> UnsolicitedLogin {
+        UnsolicitedLogin::RedirectTo("/".to_owned())
+    }
+}
+
+/// Configuration for the routes built by `session_auth_router_with_config`.
+#[derive(Clone)]
+pub struct AuthRouterConfig {
+    pub(crate) attribute_filter: Option<AttributeFilter>,
+    pub(crate) return_paths: ReturnPathPolicy,
+    pub(crate) return_parameter: String,
+    pub(crate) unsolicited_login: UnsolicitedLogin,
+    pub(crate) role_mapping: Option<RoleMapping>,
+    pub(crate) secure_cookies: bool,
+    pub(crate) logout_receiver: Option<Arc<dyn LogoutReceiver>>,
+    pub(crate) sp_logout: Option<String>,
+    pub(crate) sp_session_index: Option<SpSessionIndex>,
+    pub(crate) notification_secret: Option<String>,
+    pub(crate) loopback_notifications: bool,
+    pub(crate) sp_handler_path: String,
+    pub(crate) sp_headers: SpHeaders,
+    pub(crate) post_data_store: Option<PostDataStore>,
+}
+
+impl AuthRouterConfig {
+    pub fn new() -> AuthRouterConfig {
+        AuthRouterConfig {
+            attribute_filter: None,
+            return_paths: ReturnPathPolicy::default(),
+            return_parameter: "return_path".to_owned(),
+            unsolicited_login: UnsolicitedLogin::default(),
+            role_mapping: None,
+            secure_cookies: true,
+            logout_receiver: None,
+            sp_logout: None,
+            sp_session_index: None,
+            notification_secret: None,
+            loopback_notifications: false,
+            sp_handler_path: "/Shibboleth.sso".to_owned(),
+            sp_headers: SpHeaders::new(),
+            post_data_store: None,
+        }
+    }
+
+    /// Deserializes attributes only from the headers identified by `filter`, rather than from
+    /// every request header.
+    pub fn with_attribute_filter(mut self, filter: AttributeFilter) -> AuthRouterConfig {
+        self.attribute_filter = Some(filter);
+        self
+    }
+
+    /// Validates the return path of each login against `policy`. By default, only relative paths
+    /// are allowed.
+    pub fn with_return_path_policy(mut self, policy: ReturnPathPolicy) -> AuthRouterConfig {
+        self.return_paths = policy;
+        self
+    }
+
+    /// Reads the return path from the query string parameter `return_parameter`, which must match
+    /// `ShibblewareBuilder::return_parameter`. Defaults to `return_path`.
+    pub fn with_return_parameter(mut self, return_parameter: &str) -> AuthRouterConfig {
+        self.return_parameter = return_parameter.to_owned();
+        self
+    }
+
+    /// Handles logins which weren't started by `Shibbleware` in the same session with
+    /// `unsolicited_login`. By default, they're redirected to `/` without being received.
+    pub fn with_unsolicited_login(
+        mut self,
+        unsolicited_login: UnsolicitedLogin,
+    ) -> AuthRouterConfig {
+        self.unsolicited_login = unsolicited_login;
+        self
+    }
+
+    /// Allows the cookie which carries the session to its new identifier after login to be sent
+    /// over plain HTTP, as `NewSessionMiddleware::insecure` does for the session cookie. This is
+    /// only suitable for development.
+    pub fn insecure(mut self) -> AuthRouterConfig {
+        self.secure_cookies = false;
+        self
+    }
+
+    /// Clears the session with `logout_receiver` when the user logs out. By default, the session
+    /// is replaced with the default value of the session type.
+    pub fn with_logout_receiver<L>(mut self, logout_receiver: L) -> AuthRouterConfig
+    where
+        L: LogoutReceiver + 'static,
+    {
+        self.logout_receiver = Some(Arc::new(logout_receiver));
+        self
+    }
+
+    /// Sends the user to the `Logout` handler of the SP at `handler_path`, such as
+    /// `/Shibboleth.sso`, after logging them out of the application. This ends the SP session and
+    /// starts SAML single logout, after which the SP returns the user to the `return` parameter.
+    pub fn with_sp_logout(mut self, handler_path: &str) -> AuthRouterConfig {
+        self.sp_logout = Some(handler_path.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// The path of the SP handler, which must match `handlerURL` in `shibboleth2.xml`. A
+    /// front-channel logout notification may only return the browser to a location beneath it.
+    /// Defaults to `/Shibboleth.sso`.
+    pub fn with_sp_handler_path(mut self, handler_path: &str) -> AuthRouterConfig {
+        self.sp_handler_path = handler_path.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Reads the SP session of each login from the headers named by `sp_headers`, which must match
+    /// `ShibblewareBuilder::sp_headers`. See `SpHeaders`.
+    pub fn with_sp_headers(mut self, sp_headers: SpHeaders) -> AuthRouterConfig {
+        self.sp_headers = sp_headers;
+        self
+    }
+
+    /// Records the SP session of each login in `index`, and marks SP sessions as logged out when
+    /// the SP sends a logout notification to `/notify`. The same index must be given to
+    /// `ShibblewareBuilder::sp_session_index`, which invalidates the application sessions bound to
+    /// them.
+    pub fn with_sp_session_index(mut self, index: SpSessionIndex) -> AuthRouterConfig {
+        self.sp_session_index = Some(index);
+        self
+    }
+
+    /// Accepts back-channel logout notifications at `/notify` only when they carry `secret` in the
+    /// `secret` query parameter, which is added to the `Location` of `<Notify Channel="back"/>`,
+    /// such as `/auth/notify?secret=...`. Without a secret, or `allow_loopback`, every
+    /// notification is rejected.
+    ///
+    /// The SP sends notifications without a session, so mod_shib mustn't require one for
+    /// `/notify`, or it would redirect the notification to the identity provider. Where the rest of
+    /// `/auth` is served with `requireSession`, exclude it. With Apache:
+    ///
+    /// ```text
+    /// <Location /auth/notify>
+    ///   AuthType shibboleth
+    ///   ShibRequestSetting requireSession false
+    ///   Require shibboleth
+    /// </Location>
+    /// ```
+    pub fn with_notification_secret<S>(mut self, secret: S) -> AuthRouterConfig
+    where
+        S: Into<String>,
+    {
+        self.notification_secret = Some(secret.into());
+        self
+    }
+
+    /// Accepts back-channel logout notifications at `/notify` from loopback without a secret, for
+    /// an application which `shibd` reaches directly on the same host. Every request proxied by a
+    /// web server on the same host also comes from loopback, so this is unsafe behind a reverse
+    /// proxy. A secret, when configured, is required regardless.
+    pub fn allow_loopback(mut self) -> AuthRouterConfig {
+        self.loopback_notifications = true;
+        self
+    }
+
+    /// Submits forms which were interrupted by a login again, once the login is received, from
+    /// `store`. The same store must be given to `ShibblewareBuilder::post_data_store`.
+    pub fn with_post_data_store(mut self, store: PostDataStore) -> AuthRouterConfig {
+        self.post_data_store = Some(store);
+        self
+    }
+
+    /// Grants roles to each user who logs in, according to `role_mapping`. The roles are kept in
+    /// the session, and are available from `ShibSessionState::roles`.
+    pub fn with_role_mapping(mut self, role_mapping: RoleMapping) -> AuthRouterConfig {
+        self.role_mapping = Some(role_mapping);
+        self
+    }
+}
+
+impl Default for AuthRouterConfig {
+    fn default() -> AuthRouterConfig {
+        AuthRouterConfig::new()
+    }
+}
 
 /// Builds the subrouter for the Shibboleth-protected part of application, where new sessions will
 /// be received for processing.
+///
+/// The subrouter serves `/login`, which passes the user's attributes to the receiver and then
+/// finishes the login, redirecting to the relative path in the `return_path` query parameter. It
+/// doesn't use the session, so it has none of the protections of `session_auth_router`, which
+/// should be preferred by applications which use `Shibbleware`.
 pub fn auth_router<A, R>(r: R) -> Router
 where
     A: for<'de> Deserialize<'de> + Debug + 'static,
     R: Receiver<A> + Copy + RefUnwindSafe + 'static,
 {
+    build_simple_router(|route| {
+        route.get("/login").to(LoginHandler::new(r));
+    })
+}
+
+/// Builds the subrouter for the Shibboleth-protected part of application, receiving logins into
+/// the Gotham session of type `T`, which is the session type used with `Shibbleware`.
+///
+/// The subrouter must be mounted behind the session middleware for `T`, and `backend` must be the
+/// backend given to that middleware, or one which shares its storage. The session type can't be
+/// inferred from the receiver, so it must be named, as in
+/// `session_auth_router::<Session, _, _, _>(receiver, backend)`. Its routes respond with
+/// `500 Internal Server Error` when the session middleware didn't run.
+///
+/// The subrouter serves `/login`, the login route, `/resume`, which moves the session of a login to
+/// a new session identifier before finishing it, `/logout`, which logs the user out on a `POST`
+/// and then redirects to the validated `return` parameter, and otherwise asks them to confirm,
+/// `/confirm`, which submits an action again once a user who was interrupted by a login confirms
+/// it, and `/notify`, which receives logout notifications from the SP. Back-channel notifications
+/// are `POST` requests, which only the SP should be able to make. Front-channel notifications are
+/// `GET` requests from the browser.
+///
+/// Between `/login` and `/resume`, the contents of the session are kept in `backend` for up to a
+/// minute, so the two requests may be served by different instances of the application.
+pub fn session_auth_router<T, A, R, B>(r: R, backend: B) -> Router
+where
+    T: AuthenticatedSession,
+    A: for<'de> Deserialize<'de> + Debug + 'static,
+    R: Receiver<A> + Copy + RefUnwindSafe + 'static,
+    B: NewBackend + Send + 'static,
+{
+    session_auth_router_with_config::<T, A, R, B>(r, backend, AuthRouterConfig::default())
+}
+
+/// Builds the subrouter for the Shibboleth-protected part of application, as
+/// `session_auth_router` does, with the provided configuration.
+pub fn session_auth_router_with_config<T, A, R, B>(
+    r: R,
+    backend: B,
+    config: AuthRouterConfig,
+) -> Router
+where
+    T: AuthenticatedSession,
+    A: for<'de> Deserialize<'de> + Debug + 'static,
+    R: Receiver<A> + Copy + RefUnwindSafe + 'static,
+    B: NewBackend + Send + 'static,
+{
+    if T::default().shib_state().is_none() {
+        warn!(
+            "the session type doesn't provide ShibSessionState, so logins aren't bound to the \
+             session which started them and can be forced on a user"
+        );
+    }
+
+    if config.sp_session_index.is_some() && config.notification_secret.is_none()
+        && !config.loopback_notifications
+    {
+        warn!(
+            "no notification secret is configured, so logout notifications from the SP will be \
+             rejected"
+        );
+    }
+
+    let config = Arc::new(config);
+    let handoff = SessionHandoff::new(backend);
+
     build_simple_router(|route| {
         route
             .get("/login")
-            .with_query_string_extractor::<ReturnInfo>()
-            .to(LoginHandler::new(r));
+            .to_new_handler(SessionLoginHandler::<T, A, R>::new(
+                r,
+                handoff.clone(),
+                config.clone(),
+            ));
+
+        route
+            .get("/resume")
+            .to_new_handler(ResumeHandler::<T, A, R>::new(r, handoff, config.clone()));
+
+        route
+            .get("/logout")
+            .to_new_handler(LogoutHandler::<T>::new(config.clone()));
+
+        route
+            .post("/logout")
+            .to_new_handler(LogoutHandler::<T>::new(config.clone()));
+
+        route
+            .get("/notify")
+            .to_new_handler(FrontChannelLogoutHandler::<T>::new(config.clone()));
+
+        route
+            .post("/notify")
+            .to_new_handler(LogoutNotificationHandler::new(config.clone()));
+
+        route
+            .get("/confirm")
+            .to_new_handler(ConfirmHandler::new(config));
     })
 }
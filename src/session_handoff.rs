@@ -0,0 +1,352 @@
+//! Rotation of the Gotham session when a login is received, protecting against session fixation.
+//!
+//! A session identifier which was planted in the user's browser before login would otherwise
+//! remain valid afterwards, and be authenticated for whoever planted it. Gotham 0.2 can discard a
+//! session, but can't give a request a new session identifier, so the rotation spans two requests:
+//!
+//! 1. The request which receives the login discards its session, which drops it from the backend
+//!    and expires the session cookie. The contents of the session are kept in the session backend,
+//!    under a random token which is given to the browser in a short-lived cookie.
+//! 2. The browser is redirected to a request which arrives without a session cookie, so Gotham
+//!    creates a session with a new identifier. The contents are moved into it, and dropped from the
+//!    backend, and the cookie is cleared.
+//!
+//! The planter of the original session identifier is left with a session which no longer exists,
+//! and never receives the token. Keeping the contents in the session backend means that the second
+//! request may be served by another instance of the application, and that logins in progress are
+//! limited only by the backend.
+
+use std::io;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::{future, Future};
+use gotham::middleware::session::{Backend, NewBackend, SessionData, SessionIdentifier};
+use gotham::state::{request_id, FromState, State};
+use hyper::Headers;
+use serde_json;
+
+use authenticated_session::AuthenticatedSession;
+use headers::header_value;
+use post_data::PreservedPost;
+use receiver::ReceiverFailed;
+use session_state::random_nonce;
+
+/// How long a handoff is kept for, in seconds. The browser follows the redirect which completes the
+/// rotation immediately, so this only needs to allow for a slow network.
+const RETENTION: i64 = 60;
+
+/// The contents of a session which was discarded when a login was received, on their way to the
+/// session which replaces it.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Handoff {
+    /// The session, serialized as JSON.
+    pub(crate) session: String,
+    /// The validated return path of the login.
+    pub(crate) return_path: Option<String>,
+    /// The form submission which the login interrupted, if any.
+    pub(crate) preserved_post: Option<PreservedPost>,
+    /// When the session was discarded, as a Unix timestamp.
+    created: i64,
+}
+
+impl Handoff {
+    fn is_expired(&self, now: i64) -> bool {
+        now - self.created >= RETENTION
+    }
+}
+
+/// Creates instances of the session backend, without naming its type.
+trait NewHandoffBackend: Send + Sync + RefUnwindSafe {
+    fn new_backend(&self) -> io::Result<Box<dyn Backend + Send>>;
+}
+
+impl<B> NewHandoffBackend for B
+where
+    B: NewBackend + Send + 'static,
+{
+    fn new_backend(&self) -> io::Result<Box<dyn Backend + Send>> {
+        NewBackend::new_backend(self).map(|backend| Box::new(backend) as Box<dyn Backend + Send>)
+    }
+}
+
+/// Sessions which are being moved to a new session identifier, held in the session backend
+/// between the two requests of a rotation.
+#[derive(Clone)]
+pub(crate) struct SessionHandoff {
+    backend: Arc<dyn NewHandoffBackend>,
+}
+
+impl SessionHandoff {
+    /// Keeps handoffs in `backend`, which should be the backend of the session middleware, so that
+    /// they're shared with every instance of the application which shares its sessions.
+    pub(crate) fn new<B>(backend: B) -> SessionHandoff
+    where
+        B: NewBackend + Send + 'static,
+    {
+        SessionHandoff {
+            backend: Arc::new(backend),
+        }
+    }
+
+    /// Keeps `handoff`, returning the token under which it's kept, or `None` when the backend
+    /// fails.
+    pub(crate) fn insert(&self, id: &str, handoff: &Handoff) -> Option<String> {
+        let token = random_nonce();
+
+        let handoff = match serde_json::to_vec(handoff) {
+            Ok(handoff) => handoff,
+            Err(e) => {
+                error!("[{}] failed to serialize the session for rotation: {}", id, e);
+                return None;
+            }
+        };
+
+        let persisted = self.backend
+            .new_backend()
+            .map_err(|e| format!("{}", e))
+            .and_then(|backend| {
+                backend
+                    .persist_session(identifier(&token), &handoff)
+                    .map_err(|e| format!("{:?}", e))
+            });
+
+        match persisted {
+            Ok(()) => Some(token),
+            Err(e) => {
+                error!("[{}] failed to keep the session for rotation: {}", id, e);
+                None
+            }
+        }
+    }
+
+    /// Retrieves and discards the session kept under `token`. Resolves to `None` when there's no
+    /// such session or it has expired, and fails when the backend does.
+    pub(crate) fn take(
+        &self,
+        id: &str,
+        token: &str,
+    ) -> Box<dyn Future<Item = Option<Handoff>, Error = ()> + Send> {
+        let id = id.to_owned();
+        let identifier = identifier(token);
+
+        let backend = match self.backend.new_backend() {
+            Ok(backend) => backend,
+            Err(e) => {
+                error!("[{}] failed to read the session for rotation: {}", id, e);
+                return Box::new(future::err(()));
+            }
+        };
+
+        let read = backend.read_session(identifier.clone());
+
+        let f = read.then(move |read| {
+            let handoff = match read {
+                Ok(Some(handoff)) => handoff,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    error!("[{}] failed to read the session for rotation: {:?}", id, e);
+                    return Err(());
+                }
+            };
+
+            // The token can only be used once, even if the handoff turns out to have expired.
+            if let Err(e) = backend.drop_session(identifier) {
+                error!("[{}] failed to drop the session after rotation: {:?}", id, e);
+                return Err(());
+            }
+
+            match serde_json::from_slice::<Handoff>(&handoff) {
+                Ok(ref handoff) if handoff.is_expired(Utc::now().timestamp()) => Ok(None),
+                Ok(handoff) => Ok(Some(handoff)),
+                Err(e) => {
+                    error!("[{}] failed to read the session for rotation: {}", id, e);
+                    Err(())
+                }
+            }
+        });
+
+        Box::new(f)
+    }
+}
+
+/// The identifier of the handoff kept under `token`, which can't be mistaken for the identifier of
+/// a session created by Gotham.
+fn identifier(token: &str) -> SessionIdentifier {
+    SessionIdentifier {
+        value: format!("shib-gotham-handoff-{}", token),
+    }
+}
+
+/// The cookie which carries a handoff token to the resume route.
+const HANDOFF_COOKIE: &str = "_shib_gotham_login";
+
+/// The value of a `Set-Cookie` header which gives the browser `token`.
+pub(crate) fn set_handoff_cookie(token: &str, secure: bool) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/; Max-Age=60; HttpOnly; SameSite=Lax",
+        HANDOFF_COOKIE, token
+    );
+
+    if secure {
+        cookie.push_str("; Secure");
+    }
+
+    cookie
+}
+
+/// The value of a `Set-Cookie` header which removes the handoff cookie from the browser.
+pub(crate) fn clear_handoff_cookie() -> String {
+    format!("{}=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax", HANDOFF_COOKIE)
+}
+
+/// Reads the handoff token from the request's cookies.
+pub(crate) fn handoff_token(headers: &Headers) -> Option<&str> {
+    header_value(headers, "Cookie")
+        .into_iter()
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| {
+            let mut split = cookie.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(name), Some(value)) if name == HANDOFF_COOKIE && !value.is_empty() => {
+                    Some(value)
+                }
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Discards the session of a request which received a login, keeping its contents in `handoff`
+/// along with `return_path` and any preserved form submission. Returns the value of the
+/// `Set-Cookie` header which gives the browser the handoff token.
+///
+/// `SessionData` is no longer in `state` afterwards, so nothing may use the session for the rest
+/// of the request.
+pub(crate) fn discard_session<T>(
+    handoff: &SessionHandoff,
+    secure_cookies: bool,
+    state: &mut State,
+    return_path: Option<String>,
+) -> Result<String, ReceiverFailed>
+where
+    T: AuthenticatedSession,
+{
+    let id = request_id(state).to_owned();
+
+    let session = match serde_json::to_string(&**SessionData::<T>::borrow_from(state)) {
+        Ok(session) => session,
+        Err(e) => {
+            error!("[{}] failed to serialize the session for rotation: {}", id, e);
+            return Err(ReceiverFailed);
+        }
+    };
+
+    let kept = Handoff {
+        session,
+        return_path,
+        preserved_post: state.try_take::<PreservedPost>(),
+        created: Utc::now().timestamp(),
+    };
+
+    let token = match handoff.insert(&id, &kept) {
+        Some(token) => token,
+        None => return Err(ReceiverFailed),
+    };
+
+    // Should the session outlive a failed discard, the handoff expires unused.
+    if let Err(e) = SessionData::<T>::take_from(state).discard(state) {
+        error!("[{}] failed to discard the session at login: {:?}", id, e);
+        return Err(ReceiverFailed);
+    }
+
+    Ok(set_handoff_cookie(&token, secure_cookies))
+}
+
+/// Replaces the session of this request with the contents of the discarded session. The request
+/// arrives without the discarded session's cookie, so its session has a new identifier.
+pub(crate) fn restore_session<T>(state: &mut State, handoff: &Handoff) -> Result<(), ReceiverFailed>
+where
+    T: AuthenticatedSession,
+{
+    match serde_json::from_str::<T>(&handoff.session) {
+        Ok(session) => {
+            *SessionData::<T>::borrow_mut_from(state) = session;
+            Ok(())
+        }
+        Err(e) => {
+            error!(
+                "[{}] failed to restore the session after rotation: {}",
+                request_id(state),
+                e
+            );
+            Err(ReceiverFailed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::middleware::session::MemoryBackend;
+
+    fn handoff(created: i64) -> Handoff {
+        Handoff {
+            session: "{}".to_owned(),
+            return_path: Some("/protected".to_owned()),
+            preserved_post: Some(PreservedPost {
+                path: "/protected".to_owned(),
+                body: b"a=1".to_vec(),
+            }),
+            created,
+        }
+    }
+
+    #[test]
+    fn test_cookies() {
+        assert_eq!(
+            set_handoff_cookie("abc123", true),
+            "_shib_gotham_login=abc123; Path=/; Max-Age=60; HttpOnly; SameSite=Lax; Secure"
+        );
+        assert_eq!(
+            set_handoff_cookie("abc123", false),
+            "_shib_gotham_login=abc123; Path=/; Max-Age=60; HttpOnly; SameSite=Lax"
+        );
+
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", "_gotham_session=planted; _shib_gotham_login=abc123");
+        assert_eq!(handoff_token(&headers), Some("abc123"));
+
+        let mut headers = Headers::new();
+        headers.set_raw("Cookie", "_gotham_session=planted; _shib_gotham_login=");
+        assert_eq!(handoff_token(&headers), None);
+        assert_eq!(handoff_token(&Headers::new()), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let backend = MemoryBackend::default();
+        let store = SessionHandoff::new(backend.clone());
+        let now = Utc::now().timestamp();
+
+        let token = store.insert("test", &handoff(now)).unwrap();
+
+        // The handoff is visible to every instance which shares the backend.
+        let taken = SessionHandoff::new(backend)
+            .take("test", &token)
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(taken.session, "{}");
+        assert_eq!(taken.return_path, Some("/protected".to_owned()));
+        assert_eq!(taken.preserved_post.unwrap().body, b"a=1".to_vec());
+        assert!(store.take("test", &token).wait().unwrap().is_none());
+
+        // Expired handoffs are discarded without being used.
+        let token = store.insert("test", &handoff(now - RETENTION)).unwrap();
+        assert!(store.take("test", &token).wait().unwrap().is_none());
+        assert!(store.take("test", &token).wait().unwrap().is_none());
+    }
+}
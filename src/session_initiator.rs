@@ -0,0 +1,154 @@
+use percent_encoding::{utf8_percent_encode, QUERY_ENCODE_SET};
+
+define_encode_set! {
+    /// Encodes a query string value which may itself contain an encoded query string, such as the
+    /// `target` of a SessionInitiator.
+    pub TARGET_ENCODE_SET = [QUERY_ENCODE_SET] | {'&', '=', ';', '%', '+', '?', '#'}
+}
+
+/// Options for sending users directly to the Shibboleth SP's SessionInitiator, rather than
+/// relying on the web server to start a session when the login route is requested.
+///
+/// With a `SessionInitiator`, `Shibbleware` redirects to `/Shibboleth.sso/Login` with the login
+/// route as the `target`, so the application chooses the identity provider and the strength of
+/// authentication for each route. The login route then needn't `requireSession`, and must not for
+/// passive logins, as described by `ShibblewareBuilder::passive`.
+#[derive(Clone, Debug)]
+pub struct SessionInitiator {
+    handler_path: String,
+    entity_id: Option<String>,
+    force_authn: bool,
+    is_passive: bool,
+    authn_context_class_ref: Option<String>,
+    name_id_format: Option<String>,
+}
+
+impl SessionInitiator {
+    /// Creates a SessionInitiator for the SP's default handler path of `/Shibboleth.sso`.
+    pub fn new() -> SessionInitiator {
+        SessionInitiator {
+            handler_path: "/Shibboleth.sso".to_owned(),
+            entity_id: None,
+            force_authn: false,
+            is_passive: false,
+            authn_context_class_ref: None,
+            name_id_format: None,
+        }
+    }
+
+    /// Uses the SP handler at `handler_path`, which must match `handlerURL` in `shibboleth2.xml`.
+    pub fn handler_path(mut self, handler_path: &str) -> SessionInitiator {
+        self.handler_path = handler_path.trim_end_matches('/').to_owned();
+        self
+    }
+
+    /// Authenticates with the identity provider `entity_id`, bypassing discovery.
+    pub fn entity_id(mut self, entity_id: &str) -> SessionInitiator {
+        self.entity_id = Some(entity_id.to_owned());
+        self
+    }
+
+    /// Requires the identity provider to authenticate the user again, even if it has a session.
+    pub fn force_authn(mut self, force_authn: bool) -> SessionInitiator {
+        self.force_authn = force_authn;
+        self
+    }
+
+    /// Requires the identity provider to authenticate the user without interacting with them.
+    #[allow(clippy::wrong_self_convention)]
+    pub fn is_passive(mut self, is_passive: bool) -> SessionInitiator {
+        self.is_passive = is_passive;
+        self
+    }
+
+    /// Requests authentication with the context class `authn_context_class_ref`, such as
+    /// `https://refeds.org/profile/mfa`.
+    pub fn authn_context_class_ref(mut self, authn_context_class_ref: &str) -> SessionInitiator {
+        self.authn_context_class_ref = Some(authn_context_class_ref.to_owned());
+        self
+    }
+
+    /// Requests a NameID in the format `name_id_format`.
+    pub fn name_id_format(mut self, name_id_format: &str) -> SessionInitiator {
+        self.name_id_format = Some(name_id_format.to_owned());
+        self
+    }
+
+    /// Builds the URL which starts a session and then sends the user to `target`.
+    pub(crate) fn url(&self, target: &str) -> String {
+        let mut url = format!(
+            "{}/Login?target={}",
+            self.handler_path,
+            utf8_percent_encode(target, TARGET_ENCODE_SET)
+        );
+
+        if let Some(ref entity_id) = self.entity_id {
+            push_parameter(&mut url, "entityID", entity_id);
+        }
+
+        if self.force_authn {
+            push_parameter(&mut url, "forceAuthn", "true");
+        }
+
+        if self.is_passive {
+            push_parameter(&mut url, "isPassive", "true");
+        }
+
+        if let Some(ref authn_context_class_ref) = self.authn_context_class_ref {
+            push_parameter(&mut url, "authnContextClassRef", authn_context_class_ref);
+        }
+
+        if let Some(ref name_id_format) = self.name_id_format {
+            push_parameter(&mut url, "NameIDFormat", name_id_format);
+        }
+
+        url
+    }
+}
+
+impl Default for SessionInitiator {
+    fn default() -> SessionInitiator {
+        SessionInitiator::new()
+    }
+}
+
+fn push_parameter(url: &mut String, name: &str, value: &str) {
+    url.push('&');
+    url.push_str(name);
+    url.push('=');
+    url.extend(utf8_percent_encode(value, TARGET_ENCODE_SET));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_url() {
+        let url = SessionInitiator::new().url("/auth/login?state=abc123");
+
+        assert_eq!(url, "/Shibboleth.sso/Login?target=/auth/login%3Fstate%3Dabc123");
+    }
+
+    #[test]
+    fn test_options() {
+        let url = SessionInitiator::new()
+            .handler_path("/sp/")
+            .entity_id("https://idp.example.edu.au/idp/shibboleth")
+            .force_authn(true)
+            .is_passive(true)
+            .authn_context_class_ref("https://refeds.org/profile/mfa")
+            .name_id_format("urn:oasis:names:tc:SAML:2.0:nameid-format:persistent")
+            .url("/auth/login?return_path=%2Fprotected");
+
+        assert_eq!(
+            url,
+            "/sp/Login?target=/auth/login%3Freturn_path%3D%252Fprotected\
+             &entityID=https://idp.example.edu.au/idp/shibboleth\
+             &forceAuthn=true\
+             &isPassive=true\
+             &authnContextClassRef=https://refeds.org/profile/mfa\
+             &NameIDFormat=urn:oasis:names:tc:SAML:2.0:nameid-format:persistent"
+        );
+    }
+}
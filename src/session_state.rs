@@ -0,0 +1,332 @@
+use chrono::{DateTime, TimeZone, Utc};
+use rand::{self, Rng};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+/// The number of logins which may be pending in a session at once. Starting another login
+/// discards the oldest.
+const MAX_PENDING_LOGINS: usize = 8;
+
+/// State kept by shib-gotham in the application's session.
+///
+/// Include a field of this type in the session type, and return it from
+/// `AuthenticatedSession::shib_state` and `AuthenticatedSession::shib_state_mut`, to enable the
+/// features which depend on it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShibSessionState {
+    /// The logins started by `Shibbleware` which haven't been received, oldest first. Each
+    /// unauthenticated request starts one, so parallel tabs and subresource requests each have
+    /// their own.
+    pub(crate) pending_logins: Vec<PendingLogin>,
+
+    /// Whether a passive login has been attempted in this session.
+    pub(crate) passive_attempted: bool,
+
+    /// The `Shib-AuthnContext-Class` of the most recent login.
+    pub(crate) authn_context_class: Option<String>,
+
+    /// The `Shib-Authentication-Instant` of the most recent login, as a Unix timestamp.
+    pub(crate) authentication_instant: Option<i64>,
+
+    /// The roles granted by the `RoleMapping` of the most recent login.
+    pub(crate) roles: BTreeSet<String>,
+
+    /// When the most recent login was received, as a Unix timestamp.
+    pub(crate) authenticated_at: Option<i64>,
+
+    /// When `Shibbleware` last passed an authenticated request, as a Unix timestamp.
+    pub(crate) last_seen: Option<i64>,
+
+    /// The `Shib-Session-ID` of the most recent login.
+    pub(crate) sp_session_id: Option<String>,
+
+    /// The `Shib-Identity-Provider` of the most recent login.
+    pub(crate) identity_provider: Option<String>,
+}
+
+impl ShibSessionState {
+    /// The authentication context class of the most recent login, such as
+    /// `https://refeds.org/profile/mfa`.
+    pub fn authn_context_class(&self) -> Option<&str> {
+        self.authn_context_class.as_ref().map(String::as_str)
+    }
+
+    /// The time at which the identity provider last authenticated the user, if known.
+    pub fn authentication_instant(&self) -> Option<DateTime<Utc>> {
+        self.authentication_instant.and_then(to_date_time)
+    }
+
+    /// The time at which the most recent login was received, if known.
+    pub fn authenticated_at(&self) -> Option<DateTime<Utc>> {
+        self.authenticated_at.and_then(to_date_time)
+    }
+
+    /// The time at which the user last made an authenticated request, if known.
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        self.last_seen.and_then(to_date_time)
+    }
+
+    /// The roles granted to the user by the `RoleMapping` in `AuthRouterConfig`, when they logged
+    /// in.
+    pub fn roles(&self) -> &BTreeSet<String> {
+        &self.roles
+    }
+
+    /// Determines whether the user was granted `role` when they logged in.
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.contains(role)
+    }
+
+    /// Determines whether the identity provider authenticated the user within `max_age` of now.
+    pub(crate) fn authenticated_within(&self, max_age: Duration) -> bool {
+        self.authentication_instant
+            .map_or(false, |instant| is_recent(instant, max_age))
+    }
+
+    /// Determines whether the authenticated session has outlived `lifetime` since login, or has
+    /// been idle for longer than `timeout`. A session without a recorded login has expired when
+    /// either limit is set.
+    pub(crate) fn is_expired(&self, lifetime: Option<Duration>, timeout: Option<Duration>) -> bool {
+        let outlived = lifetime.map_or(false, |lifetime| {
+            self.authenticated_at
+                .map_or(true, |authenticated_at| !is_recent(authenticated_at, lifetime))
+        });
+
+        let idle = timeout.map_or(false, |timeout| {
+            self.last_seen
+                .or(self.authenticated_at)
+                .map_or(true, |last_seen| !is_recent(last_seen, timeout))
+        });
+
+        outlived || idle
+    }
+
+    /// The ID of the SP session which the most recent login came from.
+    pub fn sp_session_id(&self) -> Option<&str> {
+        self.sp_session_id.as_ref().map(String::as_str)
+    }
+
+    /// The entity ID of the identity provider which the most recent login came from.
+    pub fn identity_provider(&self) -> Option<&str> {
+        self.identity_provider.as_ref().map(String::as_str)
+    }
+
+    /// Determines whether a request's SP session and identity provider are the ones which the
+    /// most recent login came from.
+    pub(crate) fn matches_sp_session(
+        &self,
+        sp_session_id: Option<&str>,
+        identity_provider: Option<&str>,
+    ) -> bool {
+        sp_session_id.is_some() && self.sp_session_id() == sp_session_id
+            && self.identity_provider() == identity_provider
+    }
+
+    /// Records a login which was started, discarding the oldest when too many are pending.
+    pub(crate) fn add_pending_login(&mut self, pending: PendingLogin) {
+        if self.pending_logins.len() >= MAX_PENDING_LOGINS {
+            let excess = self.pending_logins.len() + 1 - MAX_PENDING_LOGINS;
+            self.pending_logins.drain(..excess);
+        }
+
+        self.pending_logins.push(pending);
+    }
+
+    /// Removes and returns the pending login with `nonce`, if there is one.
+    pub(crate) fn take_pending_login(&mut self, nonce: &str) -> Option<PendingLogin> {
+        let i = self.pending_logins
+            .iter()
+            .position(|pending| pending.nonce == nonce)?;
+
+        Some(self.pending_logins.remove(i))
+    }
+
+    /// Determines whether the last authenticated request was recorded more than `granularity`
+    /// ago, or wasn't recorded.
+    pub(crate) fn is_stale(&self, granularity: Duration) -> bool {
+        self.last_seen
+            .map_or(true, |last_seen| !is_recent(last_seen, granularity))
+    }
+
+    /// Records that the user made an authenticated request now.
+    pub(crate) fn touch(&mut self) {
+        self.last_seen = Some(Utc::now().timestamp());
+    }
+}
+
+fn to_date_time(timestamp: i64) -> Option<DateTime<Utc>> {
+    Utc.timestamp_opt(timestamp, 0).single()
+}
+
+/// A login which was started by `Shibbleware`, and which hasn't yet been received.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct PendingLogin {
+    pub(crate) nonce: String,
+    pub(crate) return_path: String,
+    #[serde(default)]
+    pub(crate) passive: bool,
+    /// Authentication context classes, any of which the login must satisfy.
+    #[serde(default)]
+    pub(crate) required_authn_contexts: Vec<String>,
+    /// The maximum age, in seconds, of the authentication which the login must carry.
+    #[serde(default)]
+    pub(crate) max_authentication_age: Option<u64>,
+    /// The nonce of a form submission kept in the `PostDataStore`, which the login interrupted.
+    #[serde(default)]
+    pub(crate) post_data: Option<String>,
+}
+
+/// Determines whether a login with the context class `class` satisfies `required`, where any of
+/// the required classes is sufficient and an empty list requires nothing.
+pub(crate) fn authn_context_satisfied(required: &[String], class: Option<&str>) -> bool {
+    required.is_empty() || class.map_or(false, |class| required.iter().any(|r| r == class))
+}
+
+/// Parses a `Shib-Authentication-Instant`, such as `2018-03-01T02:03:04.123Z`, into a Unix
+/// timestamp.
+pub(crate) fn parse_authentication_instant(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|instant| instant.timestamp())
+}
+
+/// Determines whether the Unix timestamp `instant` is within `max_age` of now. An instant in the
+/// future, as from an identity provider with a fast clock, is recent.
+pub(crate) fn is_recent(instant: i64, max_age: Duration) -> bool {
+    Utc::now().timestamp() - instant <= max_age.as_secs() as i64
+}
+
+/// Generates an unguessable value for binding a request to the session.
+pub(crate) fn random_nonce() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authn_context_satisfied() {
+        let mfa = "https://refeds.org/profile/mfa";
+        let required = vec![
+            mfa.to_owned(),
+            "urn:oasis:names:tc:SAML:2.0:ac:classes:TimeSyncToken".to_owned(),
+        ];
+
+        assert!(authn_context_satisfied(&[], None));
+        assert!(authn_context_satisfied(&required, Some(mfa)));
+        assert!(!authn_context_satisfied(
+            &required,
+            Some("urn:oasis:names:tc:SAML:2.0:ac:classes:PasswordProtectedTransport")
+        ));
+        assert!(!authn_context_satisfied(&required, None));
+    }
+
+    #[test]
+    fn test_authentication_instant() {
+        assert_eq!(
+            parse_authentication_instant("2018-03-01T02:03:04.123Z"),
+            Some(1_519_869_784)
+        );
+        assert_eq!(parse_authentication_instant("yesterday"), None);
+
+        let now = Utc::now().timestamp();
+        let max_age = Duration::from_secs(300);
+
+        assert!(is_recent(now - 60, max_age));
+        assert!(is_recent(now + 60, max_age));
+        assert!(!is_recent(now - 600, max_age));
+    }
+
+    #[test]
+    fn test_matches_sp_session() {
+        let idp = "https://idp.example.edu.au/idp/shibboleth";
+
+        let shib_state = ShibSessionState {
+            sp_session_id: Some("_abc".to_owned()),
+            identity_provider: Some(idp.to_owned()),
+            ..ShibSessionState::default()
+        };
+
+        assert!(shib_state.matches_sp_session(Some("_abc"), Some(idp)));
+        assert!(!shib_state.matches_sp_session(Some("_def"), Some(idp)));
+        assert!(!shib_state.matches_sp_session(
+            Some("_abc"),
+            Some("https://idp.other.edu.au/idp/shibboleth")
+        ));
+        assert!(!shib_state.matches_sp_session(None, None));
+        assert!(!ShibSessionState::default().matches_sp_session(None, None));
+    }
+
+    #[test]
+    fn test_pending_logins() {
+        fn pending(nonce: usize) -> PendingLogin {
+            PendingLogin {
+                nonce: nonce.to_string(),
+                return_path: format!("/page/{}", nonce),
+                passive: false,
+                required_authn_contexts: vec![],
+                max_authentication_age: None,
+                post_data: None,
+            }
+        }
+
+        let mut shib_state = ShibSessionState::default();
+        for nonce in 0..MAX_PENDING_LOGINS + 2 {
+            shib_state.add_pending_login(pending(nonce));
+        }
+
+        // The oldest logins were discarded, and the rest can each be taken once, in any order.
+        assert_eq!(shib_state.pending_logins.len(), MAX_PENDING_LOGINS);
+        assert!(shib_state.take_pending_login("0").is_none());
+        assert!(shib_state.take_pending_login("1").is_none());
+
+        let last = (MAX_PENDING_LOGINS + 1).to_string();
+        assert_eq!(
+            shib_state.take_pending_login(&last).unwrap().return_path,
+            format!("/page/{}", last)
+        );
+        assert!(shib_state.take_pending_login(&last).is_none());
+        assert!(shib_state.take_pending_login("2").is_some());
+        assert_eq!(shib_state.pending_logins.len(), MAX_PENDING_LOGINS - 2);
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let now = Utc::now().timestamp();
+        let hour = Some(Duration::from_secs(3600));
+        let eight_hours = Some(Duration::from_secs(8 * 3600));
+
+        let mut shib_state = ShibSessionState::default();
+        assert!(!shib_state.is_expired(None, None));
+        assert!(shib_state.is_expired(eight_hours, None));
+        assert!(shib_state.is_expired(None, hour));
+
+        shib_state.authenticated_at = Some(now - 2 * 3600);
+        assert!(!shib_state.is_expired(eight_hours, None));
+        assert!(shib_state.is_expired(eight_hours, hour));
+
+        shib_state.touch();
+        assert!(!shib_state.is_expired(eight_hours, hour));
+
+        shib_state.authenticated_at = Some(now - 9 * 3600);
+        assert!(shib_state.is_expired(eight_hours, hour));
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let now = Utc::now().timestamp();
+        let minute = Duration::from_secs(60);
+
+        let mut shib_state = ShibSessionState::default();
+        assert!(shib_state.is_stale(minute));
+
+        shib_state.last_seen = Some(now - 10);
+        assert!(!shib_state.is_stale(minute));
+
+        shib_state.last_seen = Some(now - 120);
+        assert!(shib_state.is_stale(minute));
+    }
+}
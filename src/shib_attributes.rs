@@ -0,0 +1,259 @@
+use std::io;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+use futures::future;
+use hyper::{Headers, Response, StatusCode};
+use serde::Deserialize;
+
+use gotham::handler::HandlerFuture;
+use gotham::http::response::create_response;
+use gotham::middleware::{Middleware, NewMiddleware};
+use gotham::state::{request_id, FromState, State, StateData};
+
+use attributes::AttributeFilter;
+use headers::{deserialize, deserialize_filtered, HeadersDeserializationError};
+
+type MissingResponse = dyn Fn(&State, &HeadersDeserializationError) -> Response
+    + Send
+    + Sync
+    + RefUnwindSafe;
+
+trait AttributesTypePhantom<A>: Send + Sync + RefUnwindSafe {}
+
+/// The user's attributes, deserialized from the headers of the current request by
+/// `ShibAttributesMiddleware`. Borrow them in a handler with
+/// `ShibAttributes::<A>::borrow_from(&state)`.
+pub struct ShibAttributes<A>(A);
+
+impl<A> ShibAttributes<A> {
+    /// Takes ownership of the attributes.
+    pub fn into_inner(self) -> A {
+        self.0
+    }
+}
+
+impl<A> Deref for ShibAttributes<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.0
+    }
+}
+
+impl<A> StateData for ShibAttributes<A>
+where
+    A: Send + 'static,
+{
+}
+
+/// Gotham middleware for services which mod_shib protects entirely, and which don't need a Gotham
+/// session. The user's attributes are deserialized from the headers of every request, and put
+/// into `State` as `ShibAttributes<A>`.
+///
+/// There is no `AuthenticatedSession`, login route or `Receiver` in this mode. A request whose
+/// attributes can't be deserialized, such as one which mod_shib didn't authenticate, receives
+/// `403 Forbidden` or the response given to `with_missing_response`.
+pub struct ShibAttributesMiddleware<A>
+where
+    A: for<'de> Deserialize<'de> + Send + 'static,
+{
+    attribute_filter: Option<AttributeFilter>,
+    missing: Option<Arc<MissingResponse>>,
+    phantom: PhantomData<dyn AttributesTypePhantom<A>>,
+}
+
+impl<A> ShibAttributesMiddleware<A>
+where
+    A: for<'de> Deserialize<'de> + Send + 'static,
+{
+    pub fn new() -> ShibAttributesMiddleware<A> {
+        ShibAttributesMiddleware {
+            attribute_filter: None,
+            missing: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Deserializes attributes only from the headers identified by `filter`, rather than from
+    /// every request header.
+    pub fn with_attribute_filter(mut self, filter: AttributeFilter) -> ShibAttributesMiddleware<A> {
+        self.attribute_filter = Some(filter);
+        self
+    }
+
+    /// Builds the response to a request without the attributes with `missing`, which receives the
+    /// deserialization error.
+    pub fn with_missing_response<F>(mut self, missing: F) -> ShibAttributesMiddleware<A>
+    where
+        F: Fn(&State, &HeadersDeserializationError) -> Response
+            + Send
+            + Sync
+            + RefUnwindSafe
+            + 'static,
+    {
+        self.missing = Some(Arc::new(missing));
+        self
+    }
+
+    fn attributes(&self, headers: &Headers) -> Result<A, HeadersDeserializationError> {
+        match self.attribute_filter {
+            Some(ref filter) => deserialize_filtered::<A>(headers, filter),
+            None => deserialize::<A>(headers),
+        }
+    }
+}
+
+impl<A> Default for ShibAttributesMiddleware<A>
+where
+    A: for<'de> Deserialize<'de> + Send + 'static,
+{
+    fn default() -> ShibAttributesMiddleware<A> {
+        ShibAttributesMiddleware::new()
+    }
+}
+
+impl<A> Clone for ShibAttributesMiddleware<A>
+where
+    A: for<'de> Deserialize<'de> + Send + 'static,
+{
+    fn clone(&self) -> Self {
+        ShibAttributesMiddleware {
+            attribute_filter: self.attribute_filter.clone(),
+            missing: self.missing.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<A> NewMiddleware for ShibAttributesMiddleware<A>
+where
+    A: for<'de> Deserialize<'de> + Send + 'static,
+{
+    type Instance = Self;
+
+    fn new_middleware(&self) -> io::Result<Self::Instance> {
+        Ok(self.clone())
+    }
+}
+
+impl<A> Middleware for ShibAttributesMiddleware<A>
+where
+    A: for<'de> Deserialize<'de> + Send + 'static,
+{
+    fn call<Chain>(self, mut state: State, chain: Chain) -> Box<HandlerFuture>
+    where
+        Chain: FnOnce(State) -> Box<HandlerFuture>,
+    {
+        let attributes = self.attributes(Headers::borrow_from(&state));
+
+        match attributes {
+            Ok(attributes) => {
+                state.put(ShibAttributes(attributes));
+                chain(state)
+            }
+            Err(e) => {
+                warn!(
+                    "[{}] request doesn't carry the expected attributes: {:?}",
+                    request_id(&state),
+                    e
+                );
+
+                let response = match self.missing {
+                    Some(ref missing) => missing(&state, &e),
+                    None => create_response(&state, StatusCode::Forbidden, None),
+                };
+
+                Box::new(future::ok((state, response)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use gotham::pipeline::new_pipeline;
+    use gotham::pipeline::set::*;
+    use gotham::router::builder::*;
+    use gotham::test::{TestResponse, TestServer};
+    use hyper::Method;
+    use mime;
+
+    use headers::header_value;
+
+    #[derive(Deserialize)]
+    struct Attributes {
+        uid: String,
+    }
+
+    /// Responds with the `uid` attribute of the user.
+    fn uid(state: State) -> (State, Response) {
+        let uid = ShibAttributes::<Attributes>::borrow_from(&state).uid.clone();
+
+        let response = create_response(
+            &state,
+            StatusCode::Ok,
+            Some((uid.into_bytes(), mime::TEXT_PLAIN)),
+        );
+        (state, response)
+    }
+
+    fn test_server(middleware: ShibAttributesMiddleware<Attributes>) -> TestServer {
+        let (pipelines, default) = new_pipeline_set().add(new_pipeline().add(middleware).build());
+        let pipelines = finalize_pipeline_set(pipelines);
+
+        TestServer::new(build_router((default, ()), pipelines, |route| {
+            route.get("/").to(uid);
+        })).unwrap()
+    }
+
+    fn get(server: &TestServer, headers: &[(&str, &str)]) -> TestResponse {
+        let mut request = server
+            .client()
+            .build_request(Method::Get, "http://localhost/");
+
+        for &(name, value) in headers {
+            request
+                .headers_mut()
+                .set_raw(name.to_owned(), value.to_owned());
+        }
+
+        request.perform().unwrap()
+    }
+
+    #[test]
+    fn test_attributes() {
+        let server = test_server(ShibAttributesMiddleware::new());
+        let response = get(&server, &[("uid", "jdoe")]);
+
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.read_body().unwrap(), b"jdoe".to_vec());
+    }
+
+    #[test]
+    fn test_missing_attributes() {
+        let server = test_server(ShibAttributesMiddleware::new());
+        assert_eq!(get(&server, &[]).status(), StatusCode::Forbidden);
+
+        let server = test_server(ShibAttributesMiddleware::new().with_missing_response(
+            |state, _| {
+                let mut response = create_response(state, StatusCode::SeeOther, None);
+                response
+                    .headers_mut()
+                    .set_raw("Location", "/Shibboleth.sso/Login");
+                response
+            },
+        ));
+
+        let response = get(&server, &[("givenName", "Jane")]);
+        assert_eq!(response.status(), StatusCode::SeeOther);
+        assert_eq!(
+            header_value(response.headers(), "Location"),
+            Some("/Shibboleth.sso/Login")
+        );
+    }
+}
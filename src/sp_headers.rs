@@ -0,0 +1,35 @@
+/// The names of the headers in which mod_shib describes the SP session of a request.
+///
+/// By default these are the names which mod_shib uses, such as `Shib-Session-ID`. When the SP is
+/// configured with an `attributePrefix`, as for AJP, the same prefix is added to each of them, and
+/// must be given to `SpHeaders::with_prefix`. The same names must be given to
+/// `ShibblewareBuilder::sp_headers` and `AuthRouterConfig::with_sp_headers`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpHeaders {
+    pub(crate) session_id: String,
+    pub(crate) identity_provider: String,
+    pub(crate) authn_context_class: String,
+    pub(crate) authentication_instant: String,
+}
+
+impl SpHeaders {
+    pub fn new() -> SpHeaders {
+        SpHeaders::with_prefix("")
+    }
+
+    /// The header names with `prefix` added, such as `AJP_Shib-Session-ID` for the prefix `AJP_`.
+    pub fn with_prefix(prefix: &str) -> SpHeaders {
+        SpHeaders {
+            session_id: format!("{}Shib-Session-ID", prefix),
+            identity_provider: format!("{}Shib-Identity-Provider", prefix),
+            authn_context_class: format!("{}Shib-AuthnContext-Class", prefix),
+            authentication_instant: format!("{}Shib-Authentication-Instant", prefix),
+        }
+    }
+}
+
+impl Default for SpHeaders {
+    fn default() -> SpHeaders {
+        SpHeaders::new()
+    }
+}
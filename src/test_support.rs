@@ -0,0 +1,279 @@
+//! Helpers for the tests which drive the middleware and routes through Gotham's `TestServer`.
+
+use std::collections::BTreeMap;
+
+use gotham::http::response::create_response;
+use gotham::middleware::session::{MemoryBackend, NewSessionMiddleware, SessionData};
+use gotham::middleware::NewMiddleware;
+use gotham::pipeline::new_pipeline;
+use gotham::pipeline::set::*;
+use gotham::router::Router;
+use gotham::router::builder::*;
+use gotham::state::{FromState, State};
+use gotham::test::{TestResponse, TestServer};
+use hyper::{Method, Response, StatusCode};
+use mime;
+
+use authenticated_session::AuthenticatedSession;
+use headers::header_value;
+use receiver::ReceiverFailed;
+use router::{session_auth_router_with_config, AuthRouterConfig};
+use session_state::ShibSessionState;
+
+pub(crate) const SESSION_COOKIE: &str = "_gotham_session";
+
+pub(crate) const IDP: &str = "https://idp.example.edu.au/idp/shibboleth";
+
+/// The headers which mod_shib adds to a request from the SP session `_abc`, for the user `jdoe`.
+pub(crate) const SP_SESSION: &[(&str, &str)] = &[
+    ("Shib-Session-ID", "_abc"),
+    ("Shib-Identity-Provider", IDP),
+    ("uid", "jdoe"),
+];
+
+#[derive(Default, Serialize, Deserialize)]
+pub(crate) struct TestSession {
+    pub(crate) user: Option<String>,
+    pub(crate) shib: ShibSessionState,
+}
+
+impl AuthenticatedSession for TestSession {
+    fn is_authenticated(&self) -> bool {
+        self.user.is_some()
+    }
+
+    fn shib_state(&self) -> Option<&ShibSessionState> {
+        Some(&self.shib)
+    }
+
+    fn shib_state_mut(&mut self) -> Option<&mut ShibSessionState> {
+        Some(&mut self.shib)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TestAttributes {
+    uid: String,
+}
+
+/// Receives the user into `TestSession`, rejecting the user `rejected`.
+pub(crate) fn receive_user(
+    state: &mut State,
+    attributes: TestAttributes,
+) -> Result<(), ReceiverFailed> {
+    if attributes.uid == "rejected" {
+        return Err(ReceiverFailed);
+    }
+
+    SessionData::<TestSession>::borrow_mut_from(state).user = Some(attributes.uid);
+    Ok(())
+}
+
+pub(crate) fn session_middleware(
+    backend: MemoryBackend,
+) -> NewSessionMiddleware<MemoryBackend, TestSession> {
+    NewSessionMiddleware::new(backend)
+        .with_session_type::<TestSession>()
+        .insecure()
+}
+
+/// Responds with the user of the session, or `anonymous`.
+pub(crate) fn page(state: State) -> (State, Response) {
+    let user = SessionData::<TestSession>::borrow_from(&state)
+        .user
+        .clone()
+        .unwrap_or_else(|| "anonymous".to_owned());
+
+    let response = create_response(
+        &state,
+        StatusCode::Ok,
+        Some((user.into_bytes(), mime::TEXT_PLAIN)),
+    );
+    (state, response)
+}
+
+/// Builds an application with a public page at `/`, a page protected by `protected` at
+/// `/protected/page`, which also answers `OPTIONS`, and `session_auth_router` at `/auth`.
+pub(crate) fn router<M>(protected: M, config: AuthRouterConfig) -> Router
+where
+    M: NewMiddleware + Send + Sync + 'static,
+    M::Instance: Send + 'static,
+{
+    router_with_backend(protected, config, MemoryBackend::default())
+}
+
+/// Builds the application of `router`, keeping its sessions in `backend`, which may be shared with
+/// another instance of the application.
+pub(crate) fn router_with_backend<M>(
+    protected: M,
+    config: AuthRouterConfig,
+    backend: MemoryBackend,
+) -> Router
+where
+    M: NewMiddleware + Send + Sync + 'static,
+    M::Instance: Send + 'static,
+{
+    let (pipelines, default) = new_pipeline_set().add(
+        new_pipeline()
+            .add(session_middleware(backend.clone()))
+            .build(),
+    );
+    let (pipelines, protected) = pipelines.add(new_pipeline().add(protected).build());
+    let pipelines = finalize_pipeline_set(pipelines);
+
+    let protected_router = build_router((protected, (default, ())), pipelines.clone(), |route| {
+        route.get("/page").to(page);
+        route.post("/page").to(page);
+        route.options("/page").to(page);
+    });
+
+    build_router((default, ()), pipelines, |route| {
+        route.get("/").to(page);
+
+        route
+            .delegate_without_pipelines("/protected")
+            .to_router(protected_router);
+
+        route
+            .delegate("/auth")
+            .to_router(session_auth_router_with_config::<TestSession, TestAttributes, _, _>(
+                receive_user,
+                backend,
+                config,
+            ));
+    })
+}
+
+/// A client which keeps the cookies set by the server, as a browser would.
+pub(crate) struct Browser<'a> {
+    server: &'a TestServer,
+    cookies: BTreeMap<String, String>,
+}
+
+impl<'a> Browser<'a> {
+    pub(crate) fn new(server: &'a TestServer) -> Browser<'a> {
+        Browser {
+            server,
+            cookies: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn cookie(&self, name: &str) -> Option<&str> {
+        self.cookies.get(name).map(String::as_str)
+    }
+
+    pub(crate) fn set_cookie(&mut self, name: &str, value: &str) {
+        self.cookies.insert(name.to_owned(), value.to_owned());
+    }
+
+    pub(crate) fn get(&mut self, path: &str, headers: &[(&str, &str)]) -> TestResponse {
+        self.request(Method::Get, path, None, headers)
+    }
+
+    /// Submits `form` as `application/x-www-form-urlencoded`.
+    pub(crate) fn post(
+        &mut self,
+        path: &str,
+        form: &str,
+        headers: &[(&str, &str)],
+    ) -> TestResponse {
+        self.request(Method::Post, path, Some(form), headers)
+    }
+
+    pub(crate) fn request(
+        &mut self,
+        method: Method,
+        path: &str,
+        form: Option<&str>,
+        headers: &[(&str, &str)],
+    ) -> TestResponse {
+        let uri = format!("http://localhost{}", path);
+        let client = self.server.client();
+
+        let mut request = match form {
+            Some(form) => client.build_request_with_body(
+                method,
+                uri,
+                form.to_owned(),
+                mime::APPLICATION_WWW_FORM_URLENCODED,
+            ),
+            None => client.build_request(method, uri),
+        };
+
+        if !self.cookies.is_empty() {
+            let cookies = self.cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>()
+                .join("; ");
+            request.headers_mut().set_raw("Cookie", cookies);
+        }
+
+        for &(name, value) in headers {
+            request
+                .headers_mut()
+                .set_raw(name.to_owned(), value.to_owned());
+        }
+
+        let response = request.perform().unwrap();
+        self.keep_cookies(&response);
+        response
+    }
+
+    fn keep_cookies(&mut self, response: &TestResponse) {
+        let set_cookies = match response.headers().get_raw("Set-Cookie") {
+            Some(set_cookies) => set_cookies,
+            None => return,
+        };
+
+        for set_cookie in set_cookies.iter() {
+            let set_cookie = String::from_utf8_lossy(set_cookie);
+            let mut attributes = set_cookie.split(';').map(str::trim);
+
+            let (name, value) = match attributes.next().map(|pair| pair.splitn(2, '=')) {
+                Some(mut pair) => (
+                    pair.next().unwrap_or("").to_owned(),
+                    pair.next().unwrap_or("").to_owned(),
+                ),
+                None => continue,
+            };
+
+            let expired = value.is_empty()
+                || attributes.any(|attribute| attribute.eq_ignore_ascii_case("Max-Age=0"));
+
+            if expired {
+                self.cookies.remove(&name);
+            } else {
+                self.cookies.insert(name, value);
+            }
+        }
+    }
+}
+
+/// The `Location` of a redirect.
+pub(crate) fn location(response: &TestResponse) -> String {
+    header_value(response.headers(), "Location")
+        .expect("response is a redirect")
+        .to_owned()
+}
+
+pub(crate) fn body(response: TestResponse) -> String {
+    String::from_utf8(response.read_body().unwrap()).unwrap()
+}
+
+/// Logs in from an unauthenticated request for `path`, through the login and resume routes with
+/// the headers of `sp_session`, returning the response of the resume route.
+pub(crate) fn log_in(
+    browser: &mut Browser,
+    path: &str,
+    sp_session: &[(&str, &str)],
+) -> TestResponse {
+    let response = browser.get(path, &[]);
+    assert_eq!(response.status(), StatusCode::SeeOther);
+
+    let response = browser.get(&location(&response), sp_session);
+    assert_eq!(response.status(), StatusCode::SeeOther);
+    assert_eq!(location(&response), "/auth/resume");
+
+    browser.get("/auth/resume", &[])
+}